@@ -0,0 +1,210 @@
+use std::cmp::min;
+
+use syscall::error::{Error, Result, EFBIG, ENOSPC};
+
+use super::{Disk, FileSystem, MAX_FILE_SIZE};
+use super::entry::DirEntry;
+use super::extent::Extents;
+use super::fsinfo::FSINFO_UNKNOWN;
+use super::table::{FAT_FREE, FAT_EOC};
+
+/// The kind of data a cluster is allocated for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placement {
+    Dir,
+    File,
+}
+
+impl<D: Disk> FileSystem<D> {
+    /// Number of free clusters, from FSInfo when it holds a trustworthy count
+    /// and the free cluster map is not built yet, otherwise from the map,
+    /// whose count is then kept in FSInfo
+    pub fn free_clusters(&mut self) -> Result<u32> {
+        if self.free_map.is_none() {
+            if let Some(info) = self.fsinfo {
+                if info.free_count != FSINFO_UNKNOWN {
+                    return Ok(info.free_count);
+                }
+            }
+        }
+
+        let free = self.free_map()?.free();
+        let stale = match self.fsinfo {
+            Some(ref mut info) if info.free_count != free => {
+                info.free_count = free;
+                true
+            },
+            _ => false
+        };
+        if stale {
+            self.write_fsinfo()?;
+        }
+
+        Ok(free)
+    }
+
+    /// Where searching for a free cluster for `placement` starts when there
+    /// is nothing to follow on from
+    fn goal(&self, placement: Placement) -> u32 {
+        match placement {
+            Placement::Dir => self.dir_goal,
+            Placement::File => self.file_goal,
+        }
+    }
+
+    /// Claim the first free cluster at or after `goal`, wrapping around to
+    /// the start of the volume, or at the goal for `placement` if not given
+    pub fn claim_cluster(&mut self, goal: Option<u32>, placement: Placement) -> Result<u32> {
+        let clusters = self.claim_clusters(goal, 1, placement)?;
+        Ok(clusters[0])
+    }
+
+    /// Claim `count` free clusters and chain them together, in one run at or
+    /// after `goal` if there is room for it, otherwise the first ones found
+    /// from there, wrapping around to the start of the volume
+    pub fn claim_clusters(&mut self, goal: Option<u32>, count: u32, placement: Placement) -> Result<Vec<u32>> {
+        let end = self.bpb.cluster_count() + 2;
+        let goal = match goal {
+            Some(goal) if goal >= 2 && goal < end => goal,
+            _ => self.goal(placement)
+        };
+
+        let clusters = {
+            let map = self.free_map()?;
+            if map.free() < count {
+                return Err(Error::new(ENOSPC));
+            }
+
+            match map.find_run(goal, end, count).or_else(|| map.find_run(2, goal, count)) {
+                Some(first) => (first..first + count).collect(),
+                None => {
+                    let mut clusters = Vec::with_capacity(count as usize);
+                    let mut next = goal;
+                    let mut limit = end;
+                    while clusters.len() < count as usize {
+                        match map.find(next, limit) {
+                            Some(cluster) => {
+                                clusters.push(cluster);
+                                next = cluster + 1;
+                            },
+                            None if limit == end => {
+                                next = 2;
+                                limit = goal;
+                            },
+                            None => return Err(Error::new(ENOSPC))
+                        }
+                    }
+                    clusters
+                }
+            }
+        };
+
+        self.set_fat_chain(&clusters)?;
+
+        // Directories and files each carry on from where they left off, so
+        // directories stay together from the middle of the volume and do not
+        // break up the files growing from the start. Only file data moves
+        // the FSInfo hint along.
+        let last = clusters[clusters.len() - 1];
+        let next = if self.is_valid_cluster(last + 1) { last + 1 } else { 2 };
+        match placement {
+            Placement::Dir => self.dir_goal = next,
+            Placement::File => self.file_goal = next,
+        }
+        self.fsinfo_allocated(count, next, placement == Placement::File);
+        self.write_fsinfo()?;
+
+        Ok(clusters)
+    }
+
+    /// Claim a free cluster, preferably the one right after `prev`, and link
+    /// it after `prev` if given
+    pub fn allocate_cluster(&mut self, prev: Option<u32>, placement: Placement) -> Result<u32> {
+        let cluster = self.claim_cluster(prev.map(|prev| prev + 1), placement)?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster)?;
+        }
+        Ok(cluster)
+    }
+
+    /// Return every cluster of the chain starting at `start` to the free pool
+    pub fn free_chain(&mut self, start: u32) -> Result<()> {
+        let chain = self.chain(start)?;
+        for &cluster in chain.iter() {
+            self.set_fat_entry(cluster, FAT_FREE)?;
+        }
+        self.chain_generations.bump(start);
+
+        self.fsinfo_freed(chain.len() as u32);
+        self.write_fsinfo()
+    }
+
+    pub fn zero_cluster(&mut self, cluster: u32) -> Result<()> {
+        let zeroes = vec![0; self.bpb.cluster_size() as usize];
+        let offset = self.bpb.cluster_offset(cluster);
+        self.write_at(offset, &zeroes)
+    }
+
+    /// Make sure the chain of `entry` covers `len` bytes, starting one if it has none
+    pub fn ensure_clusters(&mut self, entry: &mut DirEntry, len: u64, extents: &mut Extents) -> Result<()> {
+        if len > MAX_FILE_SIZE {
+            return Err(Error::new(EFBIG));
+        }
+
+        let cluster_size = self.bpb.cluster_size();
+        let needed = (len + cluster_size - 1) / cluster_size;
+
+        let (have, last) = self.extent_end(extents, entry.cluster)?;
+        if have >= needed {
+            return Ok(());
+        }
+        let missing = (needed - have) as u32;
+
+        // Short of space, take what is left so the caller can fill it before
+        // failing with ENOSPC
+        let count = min(missing, self.free_map()?.free());
+        if count == 0 {
+            return Err(Error::new(ENOSPC));
+        }
+
+        // Lay the missing clusters out in one run if there is room for them,
+        // right after the chain if possible
+        let clusters = self.claim_clusters(last.map(|last| last + 1), count, Placement::File)?;
+        match last {
+            Some(prev) => self.set_fat_entry(prev, clusters[0])?,
+            None => entry.cluster = clusters[0]
+        }
+
+        if count < missing {
+            return Err(Error::new(ENOSPC));
+        }
+        Ok(())
+    }
+
+    /// Free the clusters of `entry` past the first `len` bytes, ending its chain
+    /// there, or dropping it altogether when `len` is 0
+    pub fn shrink_clusters(&mut self, entry: &mut DirEntry, len: u64, extents: &mut Extents) -> Result<()> {
+        if entry.cluster == 0 {
+            return Ok(());
+        }
+
+        let cluster_size = self.bpb.cluster_size();
+        let needed = (len + cluster_size - 1) / cluster_size;
+        if needed == 0 {
+            self.free_chain(entry.cluster)?;
+            entry.cluster = 0;
+            return Ok(());
+        }
+
+        let generation = self.chain_generations.current();
+        let last = self.extent_cluster(extents, entry.cluster, needed - 1)?;
+        if let Some(next) = self.next_cluster(last)? {
+            self.set_fat_entry(last, FAT_EOC)?;
+            self.free_chain(next)?;
+            self.chain_generations.bump(entry.cluster);
+            self.extents_truncated(extents, needed, generation);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use syscall::error::Result;
+
+use super::{Disk, FileSystem, le_u32};
+use super::table::FAT_FREE;
+
+/// One bit per cluster, set while the cluster is free
+pub struct FreeMap {
+    words: Vec<u64>,
+    free: u32,
+}
+
+impl FreeMap {
+    /// A map of clusters 0..end, all of them in use
+    pub fn new(end: u32) -> FreeMap {
+        FreeMap {
+            words: vec![0; (end as usize + 63) / 64],
+            free: 0,
+        }
+    }
+
+    pub fn free(&self) -> u32 {
+        self.free
+    }
+
+    pub fn is_free(&self, cluster: u32) -> bool {
+        let cluster = cluster as usize;
+        match self.words.get(cluster / 64) {
+            Some(word) => word & 1 << (cluster % 64) != 0,
+            None => false
+        }
+    }
+
+    pub fn set_free(&mut self, cluster: u32, free: bool) {
+        if self.is_free(cluster) == free {
+            return;
+        }
+
+        let index = cluster as usize;
+        if let Some(word) = self.words.get_mut(index / 64) {
+            *word ^= 1 << (index % 64);
+            if free {
+                self.free += 1;
+            } else {
+                self.free -= 1;
+            }
+        }
+    }
+
+    /// First free cluster in start..end, skipping 64 used clusters at a time
+    pub fn find(&self, start: u32, end: u32) -> Option<u32> {
+        let mut cluster = start;
+        while cluster < end {
+            let word = self.words[cluster as usize / 64] >> (cluster % 64);
+            if word == 0 {
+                cluster += 64 - cluster % 64;
+            } else {
+                let found = cluster + word.trailing_zeros();
+                return if found < end { Some(found) } else { None };
+            }
+        }
+        None
+    }
+
+    /// First run of `count` free clusters starting in start..end
+    pub fn find_run(&self, start: u32, end: u32, count: u32) -> Option<u32> {
+        let mut cluster = start;
+        while let Some(first) = self.find(cluster, end) {
+            let mut len = 1;
+            while len < count && first + len < self.words.len() as u32 * 64 && self.is_free(first + len) {
+                len += 1;
+            }
+            if len == count {
+                return Some(first);
+            }
+            cluster = first + len;
+        }
+        None
+    }
+}
+
+impl<D: Disk> FileSystem<D> {
+    /// The free cluster map, built from the FAT the first time it is needed
+    pub fn free_map(&mut self) -> Result<&mut FreeMap> {
+        if self.free_map.is_none() {
+            let map = self.read_free_map()?;
+            self.free_map = Some(map);
+        }
+        Ok(self.free_map.as_mut().unwrap())
+    }
+
+    /// Build the free cluster map by reading the whole FAT a sector at a time
+    fn read_free_map(&mut self) -> Result<FreeMap> {
+        let per_sector = self.bpb.bytes_per_sector as u32 / 4;
+        let fat_offset = self.bpb.fat_offset(self.bpb.active_fat());
+        let mut sector = vec![0; self.bpb.bytes_per_sector as usize];
+
+        let end = self.bpb.cluster_count() + 2;
+        let mut map = FreeMap::new(end);
+        let mut first = 0;
+        while first < end {
+            self.read_at(fat_offset + first as u64 * 4, &mut sector)?;
+            for cluster in first..first + per_sector {
+                if cluster >= 2 && cluster < end
+                    && le_u32(&sector, ((cluster - first) * 4) as usize) & 0x0FFF_FFFF == FAT_FREE {
+                    map.set_free(cluster, true);
+                }
+            }
+            first += per_sector;
+        }
+
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(end: u32, free: &[u32]) -> FreeMap {
+        let mut map = FreeMap::new(end);
+        for &cluster in free {
+            map.set_free(cluster, true);
+        }
+        map
+    }
+
+    #[test]
+    fn free_count() {
+        let mut map = map(200, &[2, 3, 130]);
+        assert_eq!(map.free(), 3);
+        map.set_free(3, true);
+        assert_eq!(map.free(), 3);
+        map.set_free(130, false);
+        map.set_free(130, false);
+        assert_eq!(map.free(), 2);
+        assert!(map.is_free(2) && ! map.is_free(130) && ! map.is_free(1000));
+    }
+
+    #[test]
+    fn find_skips_used_words() {
+        let map = map(300, &[5, 190]);
+        assert_eq!(map.find(2, 300), Some(5));
+        assert_eq!(map.find(6, 300), Some(190));
+        assert_eq!(map.find(6, 190), None);
+        assert_eq!(map.find(191, 300), None);
+    }
+
+    #[test]
+    fn find_run_in_range() {
+        let free: Vec<u32> = (10..13).chain(60..70).chain(100..104).collect();
+        let map = map(200, &free);
+        assert_eq!(map.find_run(2, 200, 1), Some(10));
+        assert_eq!(map.find_run(2, 200, 3), Some(10));
+        assert_eq!(map.find_run(2, 200, 4), Some(60));
+        assert_eq!(map.find_run(2, 200, 10), Some(60));
+        assert_eq!(map.find_run(2, 200, 11), None);
+        assert_eq!(map.find_run(61, 200, 4), Some(61));
+        assert_eq!(map.find_run(67, 200, 4), Some(100));
+        assert_eq!(map.find_run(2, 60, 4), None);
+    }
+
+    #[test]
+    fn find_run_across_words() {
+        let free: Vec<u32> = (60..70).collect();
+        let map = map(128, &free);
+        assert_eq!(map.find_run(2, 128, 10), Some(60));
+    }
+}
@@ -0,0 +1,100 @@
+use syscall::error::{Error, Result, EINVAL};
+
+use super::{le_u16, le_u32};
+
+/// The fields of the FAT32 boot sector that the driver relies on
+#[derive(Clone, Copy, Debug)]
+pub struct BiosParameterBlock {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fats: u8,
+    pub total_sectors: u32,
+    pub fat_size: u32,
+    pub ext_flags: u16,
+    pub root_cluster: u32,
+    pub fs_info_sector: u16,
+    pub volume_id: u32,
+}
+
+impl BiosParameterBlock {
+    /// Parse and sanity check the first sector of the partition
+    pub fn parse(sector: &[u8]) -> Result<BiosParameterBlock> {
+        if sector.len() < 512 || sector[510] != 0x55 || sector[511] != 0xAA {
+            return Err(Error::new(EINVAL));
+        }
+
+        let total_sectors = match le_u16(sector, 0x13) {
+            0 => le_u32(sector, 0x20),
+            count => count as u32
+        };
+
+        let bpb = BiosParameterBlock {
+            bytes_per_sector: le_u16(sector, 0x0B),
+            sectors_per_cluster: sector[0x0D],
+            reserved_sectors: le_u16(sector, 0x0E),
+            fats: sector[0x10],
+            total_sectors: total_sectors,
+            fat_size: le_u32(sector, 0x24),
+            ext_flags: le_u16(sector, 0x28),
+            root_cluster: le_u32(sector, 0x2C),
+            fs_info_sector: le_u16(sector, 0x30),
+            volume_id: le_u32(sector, 0x43),
+        };
+
+        // Only FAT32 is supported: no fixed root directory and a 32-bit FAT size
+        let valid = match bpb.bytes_per_sector {
+            512 | 1024 | 2048 | 4096 => true,
+            _ => false
+        } && bpb.sectors_per_cluster.is_power_of_two()
+          && bpb.reserved_sectors > 0
+          && bpb.fats > 0
+          && le_u16(sector, 0x11) == 0
+          && le_u16(sector, 0x16) == 0
+          && bpb.fat_size > 0
+          && bpb.root_cluster >= 2
+          && bpb.first_data_sector() < bpb.total_sectors;
+
+        if valid {
+            Ok(bpb)
+        } else {
+            Err(Error::new(EINVAL))
+        }
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.bytes_per_sector as u64 * self.sectors_per_cluster as u64
+    }
+
+    pub fn first_data_sector(&self) -> u32 {
+        self.reserved_sectors as u32 + self.fats as u32 * self.fat_size
+    }
+
+    /// Number of data clusters, valid cluster numbers are 2..cluster_count() + 2
+    pub fn cluster_count(&self) -> u32 {
+        (self.total_sectors - self.first_data_sector()) / self.sectors_per_cluster as u32
+    }
+
+    /// Byte offset of the given copy of the FAT
+    pub fn fat_offset(&self, fat: u8) -> u64 {
+        (self.reserved_sectors as u64 + fat as u64 * self.fat_size as u64) * self.bytes_per_sector as u64
+    }
+
+    /// When mirroring is disabled only the active FAT is read and written
+    pub fn mirroring(&self) -> bool {
+        self.ext_flags & 0x80 == 0
+    }
+
+    pub fn active_fat(&self) -> u8 {
+        if self.mirroring() {
+            0
+        } else {
+            (self.ext_flags & 0x0F) as u8
+        }
+    }
+
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        (self.first_data_sector() as u64 + (cluster as u64 - 2) * self.sectors_per_cluster as u64)
+            * self.bytes_per_sector as u64
+    }
+}
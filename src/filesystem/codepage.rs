@@ -0,0 +1,76 @@
+use std::str::FromStr;
+
+/// OEM codepage used for the bytes of 8.3 names
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codepage {
+    Cp437,
+    Cp850,
+}
+
+impl Default for Codepage {
+    fn default() -> Codepage {
+        Codepage::Cp437
+    }
+}
+
+impl FromStr for Codepage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Codepage, String> {
+        match s {
+            "437" => Ok(Codepage::Cp437),
+            "850" => Ok(Codepage::Cp850),
+            _ => Err(format!("unsupported codepage '{}'", s))
+        }
+    }
+}
+
+impl Codepage {
+    /// Characters for bytes 0x80 to 0xFF, the lower half being ASCII
+    fn table(&self) -> &'static [char; 128] {
+        match *self {
+            Codepage::Cp437 => &CP437,
+            Codepage::Cp850 => &CP850,
+        }
+    }
+
+    /// Every byte decodes to exactly one character
+    pub fn decode(&self, b: u8) -> char {
+        if b < 0x80 {
+            b as char
+        } else {
+            self.table()[(b - 0x80) as usize]
+        }
+    }
+
+    /// The byte for `c`, None if this codepage cannot represent it
+    pub fn encode(&self, c: char) -> Option<u8> {
+        if (c as u32) < 0x80 {
+            Some(c as u8)
+        } else {
+            self.table().iter().position(|&t| t == c).map(|i| (i + 0x80) as u8)
+        }
+    }
+}
+
+const CP437: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+const CP850: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', 'ø', '£', 'Ø', '×', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '®', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', 'Á', 'Â', 'À', '©', '╣', '║', '╗', '╝', '¢', '¥', '┐',
+    '└', '┴', '┬', '├', '─', '┼', 'ã', 'Ã', '╚', '╔', '╩', '╦', '╠', '═', '╬', '¤',
+    'ð', 'Ð', 'Ê', 'Ë', 'È', 'ı', 'Í', 'Î', 'Ï', '┘', '┌', '█', '▄', '¦', 'Ì', '▀',
+    'Ó', 'ß', 'Ô', 'Ò', 'õ', 'Õ', 'µ', 'þ', 'Þ', 'Ú', 'Û', 'Ù', 'ý', 'Ý', '¯', '´',
+    '\u{AD}', '±', '‗', '¾', '¶', '§', '÷', '¸', '°', '¨', '·', '¹', '³', '²', '■', '\u{A0}',
+];
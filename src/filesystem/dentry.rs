@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+
+use super::node::Node;
+
+/// Lookups remembered before the cache is emptied and starts over
+const DENTRY_CACHE_MAX: usize = 4096;
+
+/// Results of looking names up in directories, keyed by the first cluster of
+/// the directory and the lowercased name. `None` records that the name does
+/// not exist. The entry of a cached node may be outdated, only its location
+/// and names are kept in step with the disk.
+#[derive(Default)]
+pub struct DentryCache {
+    entries: BTreeMap<(u32, String), Option<Node>>,
+}
+
+impl DentryCache {
+    pub fn get(&self, dir: u32, name: &str) -> Option<&Option<Node>> {
+        self.entries.get(&(dir, name.to_lowercase()))
+    }
+
+    pub fn insert(&mut self, dir: u32, name: &str, node: Option<Node>) {
+        if self.entries.len() >= DENTRY_CACHE_MAX {
+            self.entries.clear();
+        }
+        self.entries.insert((dir, name.to_lowercase()), node);
+    }
+
+    /// Forget every lookup made in the directory starting at `dir`
+    pub fn invalidate(&mut self, dir: u32) {
+        let keys: Vec<(u32, String)> = self.entries.range((dir, String::new())..)
+            .take_while(|&(key, _)| key.0 == dir)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::extent::Extents;
+    use super::super::memdisk::{check, create_file, volume};
+    use syscall::error::ENOENT;
+
+    #[test]
+    fn invalidate_forgets_only_that_directory() {
+        let mut cache = DentryCache::default();
+        cache.insert(5, "Name", Some(Node::root(2)));
+        cache.insert(5, "gone", None);
+        cache.insert(6, "name", None);
+        assert!(cache.get(5, "NAME").map_or(false, |node| node.is_some()));
+
+        cache.invalidate(5);
+        assert!(cache.get(5, "name").is_none());
+        assert!(cache.get(5, "gone").is_none());
+        assert!(cache.get(6, "Name").map_or(false, |node| node.is_none()));
+    }
+
+    #[test]
+    fn lookups_follow_changes() {
+        let mut fs = volume(64, "");
+        let root = fs.root_node();
+
+        // A name remembered as missing turns up once created
+        assert_eq!(fs.find_node("a").unwrap_err().errno, ENOENT);
+        let a = create_file(&mut fs, "a", b"x");
+        assert_eq!(fs.find_node("A").unwrap().offset, a.offset);
+
+        // A cached node still sees its entry change
+        let ino = fs.open_inode(&a);
+        fs.write_node(ino, 1, b"yz", &mut Extents::new()).unwrap();
+        fs.close_inode(ino).unwrap();
+        assert_eq!(fs.find_node("a").unwrap().entry.size, 3);
+
+        let docs = fs.create_dir(&root, "docs").unwrap();
+        assert_eq!(fs.find_node("docs/b").unwrap_err().errno, ENOENT);
+        let a = fs.find_node("a").unwrap();
+        fs.rename_node(&a, &docs, "b").unwrap();
+        assert_eq!(fs.find_node("a").unwrap_err().errno, ENOENT);
+        let b = fs.find_node("docs/b").unwrap();
+        assert_eq!(b.entry.size, 3);
+
+        fs.remove_file(&b).unwrap();
+        assert_eq!(fs.find_node("docs/b").unwrap_err().errno, ENOENT);
+
+        // Nothing is remembered of a removed directory, whose cluster may
+        // start another one
+        create_file(&mut fs, "docs/c", b"");
+        assert!(fs.find_node("docs/c").is_ok());
+        let c = fs.find_node("docs/c").unwrap();
+        fs.remove_file(&c).unwrap();
+        assert_eq!(fs.find_node("docs/c").unwrap_err().errno, ENOENT);
+        assert!(fs.dentries.get(docs.entry.cluster, "c").is_some());
+        fs.remove_dir(&docs).unwrap();
+        assert!(fs.dentries.get(docs.entry.cluster, "c").is_none());
+        assert_eq!(fs.find_node("docs").unwrap_err().errno, ENOENT);
+        check(&mut fs);
+    }
+}
@@ -0,0 +1,672 @@
+use std::cmp::min;
+
+use syscall::error::{Error, Result, EBUSY, EEXIST, EINVAL, EIO, EISDIR, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY};
+
+use super::{Disk, FileSystem};
+use super::alloc::Placement;
+use super::name::{ShortnamePolicy, generate_short_name, validate_name};
+use super::entry::{DirEntry, LfnEntry, ATTR_DIRECTORY, ENTRY_SIZE, ENTRY_END, ENTRY_DELETED, ENTRY_KANJI_E5,
+                   LFN_LAST, is_lfn, lfn_checksum, lfn_entries};
+use super::node::Node;
+
+/// A directory may hold at most this many entries
+const DIR_ENTRIES_MAX: usize = 65536;
+
+/// Long name entries collected while walking towards their 8.3 entry
+#[derive(Default)]
+struct LfnState {
+    parts: Vec<[u16; 13]>,
+    offsets: Vec<u64>,
+    checksum: u8,
+    /// Index of the next entry expected, 0 once the name is complete
+    expected: usize,
+}
+
+impl LfnState {
+    fn push(&mut self, offset: u64, lfn: &LfnEntry) {
+        let index = lfn.index();
+        if lfn.order & LFN_LAST == LFN_LAST && index >= 1 && index <= 20 {
+            *self = LfnState::default();
+            self.parts = vec![[0; 13]; index];
+            self.checksum = lfn.checksum;
+            self.expected = index;
+        } else if self.expected == 0 || index != self.expected || lfn.checksum != self.checksum {
+            *self = LfnState::default();
+            return;
+        }
+
+        self.parts[index - 1] = lfn.chars;
+        self.offsets.push(offset);
+        self.expected -= 1;
+    }
+
+    /// The long name, if a complete one belonging to `entry` was collected
+    fn name(&self, entry: &DirEntry) -> Option<String> {
+        if self.parts.is_empty() || self.expected != 0 || self.checksum != lfn_checksum(&entry.name) {
+            return None;
+        }
+
+        let chars: Vec<u16> = self.parts.iter()
+            .flat_map(|part| part.iter().cloned())
+            .take_while(|&c| c != 0)
+            .collect();
+        Some(String::from_utf16_lossy(&chars))
+    }
+}
+
+impl<D: Disk> FileSystem<D> {
+    pub fn root_node(&self) -> Node {
+        Node::root(self.bpb.root_cluster)
+    }
+
+    /// Every slot of the directory starting at `cluster` up to the end marker,
+    /// along with its byte offset on disk
+    fn dir_slots(&mut self, cluster: u32) -> Result<Vec<(u64, [u8; ENTRY_SIZE])>> {
+        let cluster_size = self.bpb.cluster_size() as usize;
+        let mut data = vec![0; cluster_size];
+        let mut slots = Vec::new();
+
+        for cluster in self.chain(cluster)? {
+            let offset = self.bpb.cluster_offset(cluster);
+            self.read_at(offset, &mut data)?;
+            for (i, chunk) in data.chunks(ENTRY_SIZE).enumerate() {
+                if chunk[0] == ENTRY_END {
+                    return Ok(slots);
+                }
+                let mut slot = [0; ENTRY_SIZE];
+                slot.copy_from_slice(chunk);
+                slots.push((offset + (i * ENTRY_SIZE) as u64, slot));
+            }
+        }
+
+        Ok(slots)
+    }
+
+    /// The live entries of the directory starting at `cluster`, skipping
+    /// the volume label and the `.` and `..` entries
+    pub fn read_dir(&mut self, cluster: u32) -> Result<Vec<Node>> {
+        let mut nodes = Vec::new();
+        let mut lfn = LfnState::default();
+
+        for (offset, slot) in self.dir_slots(cluster)? {
+            if slot[0] == ENTRY_DELETED {
+                lfn = LfnState::default();
+                continue;
+            }
+
+            if is_lfn(&slot) {
+                lfn.push(offset, &LfnEntry::parse(&slot));
+                continue;
+            }
+
+            let entry = DirEntry::parse(&slot);
+            if ! entry.is_volume_id() && ! entry.is_dot() {
+                let codepage = self.options.codepage;
+                let short = entry.short_name();
+                let (name, lfn_offsets) = match lfn.name(&entry) {
+                    Some(name) => (name, lfn.offsets.clone()),
+                    None => (self.options.shortname.display(&short, entry.lcase, codepage), Vec::new())
+                };
+                nodes.push(Node {
+                    name: name,
+                    short_name: ShortnamePolicy::Win95.display(&short, 0, codepage),
+                    entry: entry,
+                    offset: offset,
+                    lfn_offsets: lfn_offsets,
+                    parent: cluster,
+                });
+            }
+            lfn = LfnState::default();
+        }
+
+        Ok(nodes)
+    }
+
+    pub fn find_child(&mut self, parent: &Node, name: &str) -> Result<Node> {
+        if ! parent.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        let cluster = parent.entry.cluster;
+        let cached = self.dentries.get(cluster, name).cloned();
+        let found = match cached {
+            Some(Some(mut node)) => {
+                node.entry = self.entry(node.offset)?;
+                Some(node)
+            },
+            Some(None) => None,
+            None => {
+                let found = self.read_dir(cluster)?.into_iter().find(|node| node.matches(name));
+                self.dentries.insert(cluster, name, found.clone());
+                found
+            }
+        };
+
+        found.ok_or(Error::new(ENOENT))
+    }
+
+    /// Walk `path`, relative to the root directory, to its node
+    pub fn find_node(&mut self, path: &str) -> Result<Node> {
+        let mut node = self.root_node();
+        for part in path.split('/').filter(|part| ! part.is_empty()) {
+            node = self.find_child(&node, part)?;
+        }
+        Ok(node)
+    }
+
+    /// Byte offsets of `count` consecutive free slots in the directory
+    /// starting at `cluster`, growing the directory if there are none
+    fn free_slots(&mut self, cluster: u32, count: usize) -> Result<Vec<u64>> {
+        let cluster_size = self.bpb.cluster_size() as usize;
+        let mut data = vec![0; cluster_size];
+        let mut run = Vec::new();
+
+        let chain = self.chain(cluster)?;
+        for &cluster in chain.iter() {
+            let offset = self.bpb.cluster_offset(cluster);
+            self.read_at(offset, &mut data)?;
+            for (i, slot) in data.chunks(ENTRY_SIZE).enumerate() {
+                if slot[0] == ENTRY_END || slot[0] == ENTRY_DELETED {
+                    run.push(offset + (i * ENTRY_SIZE) as u64);
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+        }
+
+        let slots_per_cluster = cluster_size / ENTRY_SIZE;
+        let mut clusters = chain.len();
+        let mut last = chain.last().cloned();
+        while run.len() < count {
+            if (clusters + 1) * slots_per_cluster > DIR_ENTRIES_MAX {
+                return Err(Error::new(ENOSPC));
+            }
+
+            // Zero the cluster before linking it, so the directory never ends in garbage
+            let cluster = self.claim_cluster(last.map(|last| last + 1), Placement::Dir)?;
+            self.zero_cluster(cluster)?;
+            if let Some(prev) = last {
+                self.set_fat_entry(prev, cluster)?;
+            }
+
+            let offset = self.bpb.cluster_offset(cluster);
+            for i in 0..min(slots_per_cluster, count - run.len()) {
+                run.push(offset + (i * ENTRY_SIZE) as u64);
+            }
+            clusters += 1;
+            last = Some(cluster);
+        }
+
+        Ok(run)
+    }
+
+    /// Add `entry` to the directory `parent` under `name`, with a long name if
+    /// the mount options call for one. The short name and case flags of
+    /// `entry` are replaced.
+    pub fn create_node(&mut self, parent: &Node, name: &str, mut entry: DirEntry) -> Result<Node> {
+        validate_name(name)?;
+        if ! parent.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        let siblings = self.read_dir(parent.entry.cluster)?;
+        if siblings.iter().any(|node| node.matches(name)) {
+            return Err(Error::new(EEXIST));
+        }
+
+        let codepage = self.options.codepage;
+        let (short, lcase, long) = match self.options.shortname.short_only(name, codepage) {
+            Some((short, lcase)) => (short, lcase, false),
+            None => {
+                let short = generate_short_name(name, codepage, |short| {
+                    siblings.iter().any(|node| &node.entry.short_name() == short)
+                }).ok_or(Error::new(EEXIST))?;
+                (short, 0, true)
+            }
+        };
+
+        entry.name = short;
+        entry.lcase = lcase;
+        if entry.name[0] == ENTRY_DELETED {
+            entry.name[0] = ENTRY_KANJI_E5;
+        }
+
+        let lfns = if long {
+            lfn_entries(name, lfn_checksum(&entry.name))
+        } else {
+            Vec::new()
+        };
+
+        self.dentries.invalidate(parent.entry.cluster);
+        let offsets = self.free_slots(parent.entry.cluster, lfns.len() + 1)?;
+        let mut data = [0; ENTRY_SIZE];
+        for (lfn, &offset) in lfns.iter().zip(offsets.iter()) {
+            lfn.serialize(&mut data);
+            self.write_at(offset, &data)?;
+        }
+        let offset = offsets[lfns.len()];
+        self.write_entry(offset, &entry)?;
+
+        Ok(Node {
+            name: if long { name.to_string() } else { self.options.shortname.display(&short, lcase, codepage) },
+            short_name: ShortnamePolicy::Win95.display(&short, 0, codepage),
+            entry: entry,
+            offset: offset,
+            lfn_offsets: offsets[..lfns.len()].to_vec(),
+            parent: parent.entry.cluster,
+        })
+    }
+
+    /// Create an empty directory named `name` in `parent`
+    pub fn create_dir(&mut self, parent: &Node, name: &str) -> Result<Node> {
+        validate_name(name)?;
+        if ! parent.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        let cluster = self.allocate_cluster(None, Placement::Dir)?;
+        let entry = self.new_entry(ATTR_DIRECTORY, cluster);
+        let node = self.init_dir(cluster, parent, &entry).and_then(|_| {
+            self.create_node(parent, name, entry)
+        });
+        if node.is_err() {
+            self.free_chain(cluster)?;
+        }
+        node
+    }
+
+    /// Zero `cluster` and write the `.` and `..` entries of the directory
+    /// `entry` in `parent` to it
+    fn init_dir(&mut self, cluster: u32, parent: &Node, entry: &DirEntry) -> Result<()> {
+        self.zero_cluster(cluster)?;
+
+        let dot = DirEntry {
+            name: *b".          ",
+            ..*entry
+        };
+        // `..` refers to the root directory as cluster 0, whatever its real cluster
+        let dotdot = DirEntry {
+            name: *b"..         ",
+            cluster: if parent.is_root() { 0 } else { parent.entry.cluster },
+            ..*entry
+        };
+
+        let offset = self.bpb.cluster_offset(cluster);
+        self.write_entry(offset, &dot)?;
+        self.write_entry(offset + ENTRY_SIZE as u64, &dotdot)
+    }
+
+    /// Mark the 8.3 entry and every LFN entry of `node` deleted, leaving its clusters alone
+    fn delete_entries(&mut self, node: &Node) -> Result<()> {
+        self.dentries.invalidate(node.parent);
+        for &offset in node.lfn_offsets.iter().chain(Some(node.offset).iter()) {
+            self.write_at(offset, &[ENTRY_DELETED])?;
+        }
+
+        Ok(())
+    }
+
+    /// Delete the entries of `old` to make room for `entry` as `name` in
+    /// `parent`, putting them back if it cannot be created
+    fn replace_entries(&mut self, old: &Node, parent: &Node, name: &str, entry: DirEntry) -> Result<Node> {
+        let mut slots = Vec::new();
+        for &offset in old.lfn_offsets.iter().chain(Some(old.offset).iter()) {
+            let mut slot = [0; ENTRY_SIZE];
+            self.read_at(offset, &mut slot)?;
+            slots.push((offset, slot));
+        }
+
+        self.delete_entries(old)?;
+        match self.create_node(parent, name, entry) {
+            Ok(node) => Ok(node),
+            Err(err) => {
+                self.dentries.invalidate(old.parent);
+                for &(offset, ref slot) in slots.iter() {
+                    self.write_at(offset, slot)?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Mark the 8.3 entry and every LFN entry of `node` deleted, then free its
+    /// clusters, or leave that to the last close when it is still open
+    pub fn remove_node(&mut self, node: &Node) -> Result<()> {
+        if node.is_root() {
+            return Err(Error::new(EBUSY));
+        }
+
+        self.delete_entries(node)?;
+        if node.is_dir() {
+            self.dentries.invalidate(node.entry.cluster);
+        }
+
+        // Open handles keep the clusters until they are closed
+        if ! self.inode_removed(node.offset, &node.entry) && node.entry.cluster != 0 {
+            self.free_chain(node.entry.cluster)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the directory `node`, which must be empty
+    pub fn remove_dir(&mut self, node: &Node) -> Result<()> {
+        if ! node.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        if ! self.read_dir(node.entry.cluster)?.is_empty() {
+            return Err(Error::new(ENOTEMPTY));
+        }
+
+        self.remove_node(node)
+    }
+
+    /// Remove the file `node`, which must not be a directory
+    pub fn remove_file(&mut self, node: &Node) -> Result<()> {
+        if node.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+
+        self.remove_node(node)
+    }
+
+    /// Whether the directory starting at `cluster` is `dir` or one of its ancestors
+    fn is_ancestor(&mut self, cluster: u32, dir: &Node) -> Result<bool> {
+        let mut current = dir.entry.cluster;
+        for _ in 0..self.bpb.cluster_count() {
+            if current == cluster {
+                return Ok(true);
+            }
+            if current == 0 || current == self.bpb.root_cluster {
+                return Ok(false);
+            }
+
+            // `..` is always the second entry of a subdirectory
+            let offset = self.bpb.cluster_offset(current) + ENTRY_SIZE as u64;
+            current = self.entry(offset)?.cluster;
+        }
+
+        // A chain of `..` entries this long can only be a loop
+        Err(Error::new(EIO))
+    }
+
+    /// Move `node` into the directory `parent` under `name`, replacing an
+    /// existing file, or empty directory when `node` is one, of that name
+    pub fn rename_node(&mut self, node: &Node, parent: &Node, name: &str) -> Result<Node> {
+        validate_name(name)?;
+        if node.is_root() {
+            return Err(Error::new(EBUSY));
+        }
+        if ! parent.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        if node.is_dir() && self.is_ancestor(node.entry.cluster, parent)? {
+            return Err(Error::new(EINVAL));
+        }
+
+        let target = self.read_dir(parent.entry.cluster)?.into_iter().find(|child| child.matches(name));
+        let moved = match target {
+            Some(ref target) if target.offset == node.offset => {
+                if target.name == name {
+                    return Ok(node.clone());
+                }
+
+                // Only the case of the name changes, so the old entries must
+                // go first or they would clash with the new ones
+                self.replace_entries(node, parent, name, node.entry)?
+            },
+            Some(target) => {
+                if target.is_dir() {
+                    if ! node.is_dir() {
+                        return Err(Error::new(EISDIR));
+                    }
+                    if ! self.read_dir(target.entry.cluster)?.is_empty() {
+                        return Err(Error::new(ENOTEMPTY));
+                    }
+                } else if node.is_dir() {
+                    return Err(Error::new(ENOTDIR));
+                }
+
+                let moved = if target.name == name {
+                    // Point the existing 8.3 entry at the data being moved, so
+                    // the name never refers to nothing
+                    let mut entry = node.entry;
+                    entry.name = target.entry.name;
+                    entry.lcase = target.entry.lcase;
+                    self.write_entry(target.offset, &entry)?;
+                    self.delete_entries(node)?;
+
+                    Node {
+                        entry: entry,
+                        parent: parent.entry.cluster,
+                        ..target.clone()
+                    }
+                } else {
+                    let moved = self.replace_entries(&target, parent, name, node.entry)?;
+                    self.delete_entries(node)?;
+                    moved
+                };
+
+                if target.is_dir() {
+                    self.dentries.invalidate(target.entry.cluster);
+                }
+                // Handles open on the target keep its clusters until they are closed
+                if ! self.inode_removed(target.offset, &target.entry) && target.entry.cluster != 0 {
+                    self.free_chain(target.entry.cluster)?;
+                }
+
+                moved
+            },
+            None => {
+                let moved = self.create_node(parent, name, node.entry)?;
+                self.delete_entries(node)?;
+                moved
+            }
+        };
+
+        self.inode_moved(node.offset, moved.offset, moved.parent);
+
+        if node.is_dir() && moved.parent != node.parent {
+            let offset = self.bpb.cluster_offset(node.entry.cluster) + ENTRY_SIZE as u64;
+            let mut dotdot = self.entry(offset)?;
+            dotdot.cluster = if parent.is_root() { 0 } else { parent.entry.cluster };
+            self.write_entry(offset, &dotdot)?;
+        }
+
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ATTR_ARCHIVE;
+    use super::super::extent::Extents;
+    use super::super::memdisk::{check, create_file, names, read_file, volume};
+    use syscall::error::{EEXIST, EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY};
+
+    #[test]
+    fn create_files() {
+        let mut fs = volume(64, "");
+        create_file(&mut fs, "readme.txt", b"hello");
+        create_file(&mut fs, "A long file name.data", &[7; 1500]);
+        assert_eq!(names(&mut fs, ""), ["readme.txt", "A long file name.data"]);
+
+        let node = fs.find_node("a LONG file name.data").unwrap();
+        assert_eq!(node.short_name, "ALONGF~1.DAT");
+        assert_eq!(node.lfn_offsets.len(), 2);
+        assert_eq!(fs.chain(node.entry.cluster).unwrap().len(), 3);
+        assert_eq!(read_file(&mut fs, "A long file name.data"), vec![7; 1500]);
+        assert_eq!(read_file(&mut fs, "readme.txt"), b"hello");
+        check(&mut fs);
+    }
+
+    #[test]
+    fn create_refuses_clashes_and_bad_names() {
+        let mut fs = volume(64, "");
+        let file = create_file(&mut fs, "readme.txt", b"hello");
+        let root = fs.root_node();
+        let entry = fs.new_entry(ATTR_ARCHIVE, 0);
+
+        assert_eq!(fs.create_node(&root, "README.TXT", entry).unwrap_err().errno, EEXIST);
+        assert_eq!(fs.create_node(&root, "a:b", entry).unwrap_err().errno, EINVAL);
+        assert_eq!(fs.create_node(&file, "inner", entry).unwrap_err().errno, ENOTDIR);
+        assert_eq!(names(&mut fs, ""), ["readme.txt"]);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn create_grows_directory() {
+        // 16 entries fit in a 512 byte cluster
+        let mut fs = volume(64, "");
+        for i in 0..20 {
+            create_file(&mut fs, &format!("F{}", i), b"");
+        }
+
+        let root = fs.bpb.root_cluster;
+        assert_eq!(fs.chain(root).unwrap().len(), 2);
+        assert_eq!(names(&mut fs, "").len(), 20);
+        assert_eq!(fs.find_node("f19").unwrap().name, "F19");
+        check(&mut fs);
+    }
+
+    #[test]
+    fn mkdir_and_rmdir() {
+        let mut fs = volume(64, "");
+        let root = fs.root_node();
+        let docs = fs.create_dir(&root, "docs").unwrap();
+        fs.create_dir(&docs, "inner").unwrap();
+        create_file(&mut fs, "docs/inner/note", b"x");
+        assert_eq!(names(&mut fs, "docs"), ["inner"]);
+        assert_eq!(fs.free_clusters().unwrap(), 60);
+        check(&mut fs);
+
+        assert_eq!(fs.remove_dir(&docs).unwrap_err().errno, ENOTEMPTY);
+        let note = fs.find_node("docs/inner/note").unwrap();
+        assert_eq!(fs.remove_dir(&note).unwrap_err().errno, ENOTDIR);
+        fs.remove_file(&note).unwrap();
+        let inner = fs.find_node("docs/inner").unwrap();
+        fs.remove_dir(&inner).unwrap();
+        assert!(names(&mut fs, "docs").is_empty());
+        check(&mut fs);
+
+        let docs = fs.find_node("docs").unwrap();
+        fs.remove_dir(&docs).unwrap();
+        assert!(names(&mut fs, "").is_empty());
+        assert_eq!(fs.free_clusters().unwrap(), 63);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn mkdir_refuses_clashes() {
+        let mut fs = volume(64, "");
+        let root = fs.root_node();
+        fs.create_dir(&root, "docs").unwrap();
+        assert_eq!(fs.create_dir(&root, "DOCS").unwrap_err().errno, EEXIST);
+        assert_eq!(fs.free_clusters().unwrap(), 62);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn rename_across_directories() {
+        let mut fs = volume(64, "");
+        let root = fs.root_node();
+        let docs = fs.create_dir(&root, "docs").unwrap();
+        let file = create_file(&mut fs, "a", b"contents");
+
+        let moved = fs.rename_node(&file, &docs, "A longer name").unwrap();
+        assert_eq!(moved.parent, docs.entry.cluster);
+        assert_eq!(names(&mut fs, ""), ["docs"]);
+        assert_eq!(names(&mut fs, "docs"), ["A longer name"]);
+        assert_eq!(read_file(&mut fs, "docs/a longer name"), b"contents");
+        check(&mut fs);
+
+        // Only the case changes, reusing the entries of the node itself
+        fs.rename_node(&moved, &docs, "A LONGER NAME").unwrap();
+        assert_eq!(names(&mut fs, "docs"), ["A LONGER NAME"]);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn rename_directory_rewrites_dotdot() {
+        let mut fs = volume(64, "");
+        let root = fs.root_node();
+        let outer = fs.create_dir(&root, "outer").unwrap();
+        let inner = fs.create_dir(&outer, "inner").unwrap();
+        let deepest = fs.create_dir(&inner, "deepest").unwrap();
+        create_file(&mut fs, "outer/inner/deepest/f", b"x");
+
+        // A directory cannot move below itself
+        assert_eq!(fs.rename_node(&outer, &deepest, "loop").unwrap_err().errno, EINVAL);
+
+        fs.rename_node(&inner, &root, "inner").unwrap();
+        assert_eq!(names(&mut fs, ""), ["outer", "inner"]);
+        assert!(names(&mut fs, "outer").is_empty());
+        assert_eq!(read_file(&mut fs, "inner/deepest/f"), b"x");
+        check(&mut fs);
+
+        let inner = fs.find_node("inner").unwrap();
+        let outer = fs.find_node("outer").unwrap();
+        fs.rename_node(&inner, &outer, "back").unwrap();
+        assert_eq!(names(&mut fs, "outer"), ["back"]);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn rename_replaces_target() {
+        let mut fs = volume(64, "");
+        let root = fs.root_node();
+        let a = create_file(&mut fs, "a", &[1; 700]);
+        create_file(&mut fs, "Target file", &[2; 1500]);
+        assert_eq!(fs.free_clusters().unwrap(), 58);
+
+        fs.rename_node(&a, &root, "target FILE").unwrap();
+        assert_eq!(names(&mut fs, ""), ["target FILE"]);
+        assert_eq!(read_file(&mut fs, "target file"), vec![1; 700]);
+        assert_eq!(fs.free_clusters().unwrap(), 61);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn rename_over_open_target() {
+        let mut fs = volume(64, "");
+        let root = fs.root_node();
+        let a = create_file(&mut fs, "a", &[1; 700]);
+        let b = create_file(&mut fs, "b", &[2; 700]);
+        let ino = fs.open_inode(&b);
+
+        fs.rename_node(&a, &root, "b").unwrap();
+        assert_eq!(fs.find_node("a").unwrap_err().errno, ENOENT);
+        assert_eq!(read_file(&mut fs, "b"), vec![1; 700]);
+
+        // The replaced file lives on for its handle until closed
+        let mut buf = vec![0; 700];
+        fs.read_node(ino, 0, &mut buf, &mut Extents::new()).unwrap();
+        assert_eq!(buf, vec![2; 700]);
+        assert_eq!(fs.free_clusters().unwrap(), 59);
+        fs.close_inode(ino).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), 61);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn rename_refuses_mismatched_targets() {
+        let mut fs = volume(64, "");
+        let root = fs.root_node();
+        let dir = fs.create_dir(&root, "dir").unwrap();
+        let full = fs.create_dir(&root, "full").unwrap();
+        create_file(&mut fs, "full/f", b"x");
+        let file = create_file(&mut fs, "file", b"y");
+
+        assert_eq!(fs.rename_node(&file, &root, "dir").unwrap_err().errno, EISDIR);
+        assert_eq!(fs.rename_node(&dir, &root, "file").unwrap_err().errno, ENOTDIR);
+        assert_eq!(fs.rename_node(&dir, &root, "full").unwrap_err().errno, ENOTEMPTY);
+        assert_eq!(fs.rename_node(&file, &file, "x").unwrap_err().errno, ENOTDIR);
+        assert_eq!(names(&mut fs, ""), ["dir", "full", "file"]);
+        assert_eq!(fs.find_node("full").unwrap().entry.cluster, full.entry.cluster);
+        check(&mut fs);
+    }
+}
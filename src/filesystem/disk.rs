@@ -0,0 +1,14 @@
+use std::fs::File;
+use std::io::{Read, Write, Seek, Result};
+
+/// Storage a filesystem can be opened on
+pub trait Disk: Read + Write + Seek {
+    /// Make everything written so far durable, not only handed to the OS
+    fn sync(&mut self) -> Result<()>;
+}
+
+impl Disk for File {
+    fn sync(&mut self) -> Result<()> {
+        self.sync_all()
+    }
+}
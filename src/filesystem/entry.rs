@@ -0,0 +1,266 @@
+use super::time::FatTime;
+use super::{le_u16, le_u32, set_le_u16, set_le_u32};
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// Windows NT case flags kept in byte 0x0C of the 8.3 entry
+pub const LCASE_BASE: u8 = 0x08;
+pub const LCASE_EXT: u8 = 0x10;
+
+/// First name byte of a deleted entry
+pub const ENTRY_DELETED: u8 = 0xE5;
+/// First name byte of the entry terminating the directory
+pub const ENTRY_END: u8 = 0x00;
+/// Stands in for a leading 0xE5 byte of a live name
+pub const ENTRY_KANJI_E5: u8 = 0x05;
+
+/// Set on the order byte of the last (first stored) LFN entry
+pub const LFN_LAST: u8 = 0x40;
+/// Number of UCS-2 characters held by one LFN entry
+pub const LFN_CHARS: usize = 13;
+/// Byte offsets of the characters held by one LFN entry
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// A decoded 8.3 directory entry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: [u8; 11],
+    pub attr: u8,
+    pub lcase: u8,
+    pub ctime_tenth: u8,
+    pub ctime: u16,
+    pub cdate: u16,
+    pub adate: u16,
+    pub cluster: u32,
+    pub mtime: u16,
+    pub mdate: u16,
+    pub size: u32,
+}
+
+impl DirEntry {
+    pub fn parse(data: &[u8]) -> DirEntry {
+        let mut name = [0; 11];
+        name.copy_from_slice(&data[..11]);
+        DirEntry {
+            name: name,
+            attr: data[0x0B],
+            lcase: data[0x0C],
+            ctime_tenth: data[0x0D],
+            ctime: le_u16(data, 0x0E),
+            cdate: le_u16(data, 0x10),
+            adate: le_u16(data, 0x12),
+            cluster: (le_u16(data, 0x14) as u32) << 16 | le_u16(data, 0x1A) as u32,
+            mtime: le_u16(data, 0x16),
+            mdate: le_u16(data, 0x18),
+            size: le_u32(data, 0x1C),
+        }
+    }
+
+    pub fn serialize(&self, data: &mut [u8]) {
+        data[..11].copy_from_slice(&self.name);
+        data[0x0B] = self.attr;
+        data[0x0C] = self.lcase;
+        data[0x0D] = self.ctime_tenth;
+        set_le_u16(data, 0x0E, self.ctime);
+        set_le_u16(data, 0x10, self.cdate);
+        set_le_u16(data, 0x12, self.adate);
+        set_le_u16(data, 0x14, (self.cluster >> 16) as u16);
+        set_le_u16(data, 0x16, self.mtime);
+        set_le_u16(data, 0x18, self.mdate);
+        set_le_u16(data, 0x1A, self.cluster as u16);
+        set_le_u32(data, 0x1C, self.size);
+    }
+
+    /// The entry standing in for the root directory, which has none on disk
+    pub fn root(cluster: u32) -> DirEntry {
+        DirEntry {
+            name: *b"           ",
+            attr: ATTR_DIRECTORY,
+            cluster: cluster,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY == ATTR_DIRECTORY
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.attr & ATTR_READ_ONLY == ATTR_READ_ONLY
+    }
+
+    pub fn is_volume_id(&self) -> bool {
+        self.attr & (ATTR_VOLUME_ID | ATTR_DIRECTORY) == ATTR_VOLUME_ID
+    }
+
+    /// The `.` and `..` entries at the start of every subdirectory
+    pub fn is_dot(&self) -> bool {
+        &self.name == b".          " || &self.name == b"..         "
+    }
+
+    pub fn created(&self) -> FatTime {
+        FatTime { date: self.cdate, time: self.ctime, hundredths: self.ctime_tenth }
+    }
+
+    pub fn set_created(&mut self, time: FatTime) {
+        self.cdate = time.date;
+        self.ctime = time.time;
+        self.ctime_tenth = time.hundredths;
+    }
+
+    pub fn modified(&self) -> FatTime {
+        FatTime { date: self.mdate, time: self.mtime, hundredths: 0 }
+    }
+
+    /// Set the modification time, which is kept in 2 second steps
+    pub fn set_modified(&mut self, time: FatTime) {
+        self.mdate = time.date;
+        self.mtime = time.time;
+    }
+
+    pub fn accessed(&self) -> FatTime {
+        FatTime { date: self.adate, time: 0, hundredths: 0 }
+    }
+
+    /// Set the access time, of which only the date is kept
+    pub fn set_accessed(&mut self, time: FatTime) {
+        self.adate = time.date;
+    }
+
+    /// The 8.3 name as stored, with the 0x05 escape undone
+    pub fn short_name(&self) -> [u8; 11] {
+        let mut name = self.name;
+        if name[0] == ENTRY_KANJI_E5 {
+            name[0] = ENTRY_DELETED;
+        }
+        name
+    }
+}
+
+/// Checksum of an 8.3 name, stored in each of its LFN entries
+pub fn lfn_checksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, &b| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b)
+    })
+}
+
+pub fn is_lfn(data: &[u8]) -> bool {
+    data[0x0B] & 0x3F == ATTR_LONG_NAME
+}
+
+/// A decoded long file name entry
+#[derive(Clone, Copy, Debug)]
+pub struct LfnEntry {
+    pub order: u8,
+    pub checksum: u8,
+    pub chars: [u16; LFN_CHARS],
+}
+
+impl LfnEntry {
+    pub fn parse(data: &[u8]) -> LfnEntry {
+        let mut chars = [0; LFN_CHARS];
+        for (c, &offset) in chars.iter_mut().zip(LFN_OFFSETS.iter()) {
+            *c = le_u16(data, offset);
+        }
+        LfnEntry {
+            order: data[0],
+            checksum: data[0x0D],
+            chars: chars,
+        }
+    }
+
+    pub fn serialize(&self, data: &mut [u8]) {
+        for b in data[..ENTRY_SIZE].iter_mut() {
+            *b = 0;
+        }
+        data[0] = self.order;
+        data[0x0B] = ATTR_LONG_NAME;
+        data[0x0D] = self.checksum;
+        for (&c, &offset) in self.chars.iter().zip(LFN_OFFSETS.iter()) {
+            set_le_u16(data, offset, c);
+        }
+    }
+
+    /// Position of this entry in the name, starting at 1
+    pub fn index(&self) -> usize {
+        (self.order & !LFN_LAST) as usize
+    }
+}
+
+/// The LFN entries holding `name`, in the order they are stored on disk
+pub fn lfn_entries(name: &str, checksum: u8) -> Vec<LfnEntry> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if units.len() % LFN_CHARS != 0 {
+        units.push(0);
+        while units.len() % LFN_CHARS != 0 {
+            units.push(0xFFFF);
+        }
+    }
+
+    let count = units.len() / LFN_CHARS;
+    (0..count).rev().map(|i| {
+        let mut chars = [0; LFN_CHARS];
+        chars.copy_from_slice(&units[i * LFN_CHARS..(i + 1) * LFN_CHARS]);
+        LfnEntry {
+            order: (i + 1) as u8 | if i + 1 == count { LFN_LAST } else { 0 },
+            checksum: checksum,
+            chars: chars,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lfn_checksum_known() {
+        assert_eq!(lfn_checksum(b"README  TXT"), 115);
+        assert_eq!(lfn_checksum(b"           "), lfn_checksum(&[b' '; 11]));
+    }
+
+    #[test]
+    fn lfn_entries_pad_and_order() {
+        let entries = lfn_entries("A long file name.txt", 0x42);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].order, 2 | LFN_LAST);
+        assert_eq!(entries[1].order, 1);
+        assert!(entries.iter().all(|entry| entry.checksum == 0x42));
+
+        let units: Vec<u16> = "A long file name.txt".encode_utf16().collect();
+        assert_eq!(&entries[1].chars[..], &units[..LFN_CHARS]);
+        assert_eq!(&entries[0].chars[..units.len() - LFN_CHARS], &units[LFN_CHARS..]);
+        assert_eq!(entries[0].chars[units.len() - LFN_CHARS], 0);
+        assert!(entries[0].chars[units.len() - LFN_CHARS + 1..].iter().all(|&c| c == 0xFFFF));
+    }
+
+    #[test]
+    fn lfn_entries_exact_fit() {
+        let name: String = ::std::iter::repeat('x').take(LFN_CHARS).collect();
+        let entries = lfn_entries(&name, 0);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].order, 1 | LFN_LAST);
+        assert!(entries[0].chars.iter().all(|&c| c == 'x' as u16));
+    }
+
+    #[test]
+    fn lfn_entry_round_trip() {
+        for entry in lfn_entries("round trip name", 0x7F) {
+            let mut data = [0xAA; ENTRY_SIZE];
+            entry.serialize(&mut data);
+            assert!(is_lfn(&data));
+            let parsed = LfnEntry::parse(&data);
+            assert_eq!(parsed.order, entry.order);
+            assert_eq!(parsed.checksum, entry.checksum);
+            assert_eq!(parsed.chars, entry.chars);
+        }
+    }
+}
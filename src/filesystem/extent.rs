@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+
+use syscall::error::{Error, Result, EIO};
+
+use super::{Disk, FileSystem};
+
+/// Chains remembered to have lost clusters before they are all forgotten
+const CHAIN_GENERATIONS_MAX: usize = 4096;
+
+/// The clusters of a chain read so far, as runs of consecutive clusters, so
+/// finding the cluster at an offset does not walk the FAT again. Freeing
+/// clusters of a chain outdates the caches of that chain only, growing a
+/// chain only adds to its end, which is read when needed.
+#[derive(Clone, Debug, Default)]
+pub struct Extents {
+    start: u32,
+    generation: u64,
+    /// (index in the chain, first cluster, length) of each run
+    runs: Vec<(u64, u32, u32)>,
+    /// Number of clusters covered by `runs`
+    len: u64,
+}
+
+impl Extents {
+    pub fn new() -> Extents {
+        Extents::default()
+    }
+
+    fn reset(&mut self, start: u32, generation: u64) {
+        self.start = start;
+        self.generation = generation;
+        self.runs.clear();
+        self.len = 0;
+    }
+
+    fn last(&self) -> Option<u32> {
+        self.runs.last().map(|&(_, cluster, len)| cluster + len - 1)
+    }
+
+    fn push(&mut self, cluster: u32) {
+        if self.last().map(|last| last + 1) == Some(cluster) {
+            if let Some(run) = self.runs.last_mut() {
+                run.2 += 1;
+            }
+        } else {
+            self.runs.push((self.len, cluster, 1));
+        }
+        self.len += 1;
+    }
+
+    fn get(&self, index: u64) -> Option<u32> {
+        if index >= self.len {
+            return None;
+        }
+        let run = match self.runs.binary_search_by_key(&index, |&(first, _, _)| first) {
+            Ok(run) => run,
+            Err(run) => run - 1
+        };
+        let (first, cluster, _) = self.runs[run];
+        Some(cluster + (index - first) as u32)
+    }
+
+    /// Forget every cluster past the first `len`
+    fn truncate(&mut self, len: u64) {
+        while let Some(&(first, _, _)) = self.runs.last() {
+            if first < len {
+                break;
+            }
+            self.runs.pop();
+        }
+        if let Some(run) = self.runs.last_mut() {
+            if run.0 + run.2 as u64 > len {
+                run.2 = (len - run.0) as u32;
+            }
+        }
+        if len < self.len {
+            self.len = len;
+        }
+    }
+}
+
+/// When each chain last lost clusters, keyed by its first cluster, for
+/// telling which `Extents` are outdated
+#[derive(Default)]
+pub struct ChainGenerations {
+    current: u64,
+    /// Every chain is taken to have changed at this generation, since the
+    /// ones from before it were forgotten
+    floor: u64,
+    changed: BTreeMap<u32, u64>,
+}
+
+impl ChainGenerations {
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    /// Record that the chain starting at `start` lost clusters
+    pub fn bump(&mut self, start: u32) {
+        self.current += 1;
+        if self.changed.len() >= CHAIN_GENERATIONS_MAX {
+            self.changed.clear();
+            self.floor = self.current;
+        }
+        self.changed.insert(start, self.current);
+    }
+
+    /// Whether what was read of the chain starting at `start` at
+    /// `generation` still holds
+    fn is_current(&self, start: u32, generation: u64) -> bool {
+        generation >= self.floor && generation >= self.changed.get(&start).cloned().unwrap_or(0)
+    }
+}
+
+impl<D: Disk> FileSystem<D> {
+    /// Make `extents` describe the chain starting at `start` as it is now
+    fn check_extents(&mut self, extents: &mut Extents, start: u32) -> Result<()> {
+        let current = self.chain_generations.current;
+        if extents.start != start || ! self.chain_generations.is_current(start, extents.generation) {
+            extents.reset(start, current);
+        } else {
+            extents.generation = current;
+        }
+        if extents.len == 0 {
+            if ! self.is_valid_cluster(start) {
+                return Err(Error::new(EIO));
+            }
+            extents.push(start);
+        }
+        Ok(())
+    }
+
+    /// Read one more cluster of the chain into `extents`, false at the end of the chain
+    fn extend_extents(&mut self, extents: &mut Extents) -> Result<bool> {
+        // A chain longer than the volume can only be a loop
+        if extents.len > self.bpb.cluster_count() as u64 {
+            return Err(Error::new(EIO));
+        }
+
+        let last = extents.last().ok_or(Error::new(EIO))?;
+        match self.next_cluster(last)? {
+            Some(next) => {
+                extents.push(next);
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    /// The cluster `index` links into the chain starting at `start`
+    pub fn extent_cluster(&mut self, extents: &mut Extents, start: u32, index: u64) -> Result<u32> {
+        self.check_extents(extents, start)?;
+        while extents.len <= index {
+            if ! self.extend_extents(extents)? {
+                return Err(Error::new(EIO));
+            }
+        }
+        extents.get(index).ok_or(Error::new(EIO))
+    }
+
+    /// Number of clusters in the chain starting at `start` and the last of them
+    pub fn extent_end(&mut self, extents: &mut Extents, start: u32) -> Result<(u64, Option<u32>)> {
+        if start == 0 {
+            return Ok((0, None));
+        }
+
+        self.check_extents(extents, start)?;
+        while self.extend_extents(extents)? {}
+        Ok((extents.len, extents.last()))
+    }
+
+    /// Bring `extents` up to date after the chain it describes was cut to
+    /// `len` clusters, if it was up to date at `generation` before the cut
+    pub fn extents_truncated(&mut self, extents: &mut Extents, len: u64, generation: u64) {
+        if extents.generation == generation {
+            extents.truncate(len);
+            extents.generation = self.chain_generations.current;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::alloc::Placement;
+    use super::super::memdisk::{check, create_file, volume};
+
+    fn extents(clusters: &[u32]) -> Extents {
+        let mut extents = Extents::new();
+        for &cluster in clusters {
+            extents.push(cluster);
+        }
+        extents
+    }
+
+    #[test]
+    fn runs_merge() {
+        let extents = extents(&[5, 6, 7, 20, 21, 9]);
+        assert_eq!(extents.runs, vec![(0, 5, 3), (3, 20, 2), (5, 9, 1)]);
+        assert_eq!(extents.len, 6);
+        assert_eq!(extents.last(), Some(9));
+    }
+
+    #[test]
+    fn get() {
+        let extents = extents(&[5, 6, 7, 20, 21, 9]);
+        let clusters: Vec<Option<u32>> = (0..7).map(|index| extents.get(index)).collect();
+        assert_eq!(clusters, vec![Some(5), Some(6), Some(7), Some(20), Some(21), Some(9), None]);
+        assert_eq!(Extents::new().get(0), None);
+    }
+
+    #[test]
+    fn truncate_within_and_between_runs() {
+        let mut cut = extents(&[5, 6, 7, 20, 21, 9]);
+        cut.truncate(4);
+        assert_eq!(cut.runs, vec![(0, 5, 3), (3, 20, 1)]);
+        assert_eq!(cut.len, 4);
+        assert_eq!(cut.get(3), Some(20));
+        assert_eq!(cut.get(4), None);
+
+        cut.truncate(3);
+        assert_eq!(cut.runs, vec![(0, 5, 3)]);
+        assert_eq!(cut.last(), Some(7));
+
+        cut.push(8);
+        assert_eq!(cut.runs, vec![(0, 5, 4)]);
+    }
+
+    #[test]
+    fn truncate_to_nothing_and_past_end() {
+        let mut cut = extents(&[5, 6]);
+        cut.truncate(10);
+        assert_eq!(cut.len, 2);
+        assert_eq!(cut.runs, vec![(0, 5, 2)]);
+
+        cut.truncate(0);
+        assert_eq!(cut.len, 0);
+        assert!(cut.runs.is_empty());
+        assert_eq!(cut.last(), None);
+    }
+
+    #[test]
+    fn freeing_outdates_only_that_chain() {
+        let mut fs = volume(64, "");
+        let a = create_file(&mut fs, "a", &[1; 3 * 512]);
+        let b = create_file(&mut fs, "b", &[2; 2 * 512]);
+        let mut kept = Extents::new();
+        let mut cut = Extents::new();
+        assert_eq!(fs.extent_end(&mut kept, a.entry.cluster).unwrap().0, 3);
+        assert_eq!(fs.extent_end(&mut cut, b.entry.cluster).unwrap().0, 2);
+
+        // Freeing b leaves what was read of a alone
+        fs.remove_file(&b).unwrap();
+        fs.check_extents(&mut kept, a.entry.cluster).unwrap();
+        assert_eq!(kept.len, 3);
+
+        // Cutting a short through another handle makes a read it again
+        let ino = fs.open_inode(&a);
+        fs.node_set_len(ino, 512, &mut Extents::new()).unwrap();
+        fs.close_inode(ino).unwrap();
+        fs.check_extents(&mut kept, a.entry.cluster).unwrap();
+        assert_eq!(kept.len, 1);
+        assert_eq!(fs.extent_end(&mut kept, a.entry.cluster).unwrap().0, 1);
+
+        // A chain starting where b did is not mistaken for it
+        let c = fs.claim_cluster(Some(b.entry.cluster), Placement::File).unwrap();
+        assert_eq!(c, b.entry.cluster);
+        assert_eq!(fs.extent_end(&mut cut, c).unwrap().0, 1);
+        fs.free_chain(c).unwrap();
+        check(&mut fs);
+    }
+}
@@ -0,0 +1,107 @@
+use std::cmp::min;
+
+use syscall::error::Result;
+
+use super::{Disk, FileSystem, le_u32, set_le_u32};
+
+/// Value of either FSInfo field when it is not known
+pub const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// Set in the second FAT entry when the volume was unmounted cleanly
+const CLEAN_SHUTDOWN: u32 = 0x0800_0000;
+
+const LEAD_SIGNATURE: u32 = 0x4161_5252;
+const STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+
+/// The free cluster hints kept in the FSInfo sector
+#[derive(Clone, Copy, Debug)]
+pub struct FsInfo {
+    pub free_count: u32,
+    pub next_free: u32,
+}
+
+impl FsInfo {
+    /// None if the sector does not carry valid FSInfo signatures
+    pub fn parse(sector: &[u8]) -> Option<FsInfo> {
+        if le_u32(sector, 0) == LEAD_SIGNATURE
+            && le_u32(sector, 0x1E4) == STRUCT_SIGNATURE
+            && le_u32(sector, 0x1FC) == TRAIL_SIGNATURE {
+            Some(FsInfo {
+                free_count: le_u32(sector, 0x1E8),
+                next_free: le_u32(sector, 0x1EC),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl<D: Disk> FileSystem<D> {
+    fn fsinfo_offset(&self) -> u64 {
+        self.bpb.fs_info_sector as u64 * self.bpb.bytes_per_sector as u64
+    }
+
+    pub fn read_fsinfo(&mut self) -> Result<Option<FsInfo>> {
+        let sector = self.bpb.fs_info_sector;
+        if sector == 0 || sector >= self.bpb.reserved_sectors {
+            return Ok(None);
+        }
+
+        let mut data = [0; 512];
+        let offset = self.fsinfo_offset();
+        self.read_at(offset, &mut data)?;
+
+        // The second FAT entry clears this bit while the volume is in use, so
+        // a volume that was not unmounted cleanly has a stale free count
+        let mut entry = [0; 4];
+        let offset = self.bpb.fat_offset(self.bpb.active_fat()) + 4;
+        self.read_at(offset, &mut entry)?;
+        let clean = le_u32(&entry, 0) & CLEAN_SHUTDOWN == CLEAN_SHUTDOWN;
+
+        Ok(FsInfo::parse(&data).map(|mut info| {
+            // Hints pointing outside the volume are as good as unknown
+            if ! clean || info.free_count > self.bpb.cluster_count() {
+                info.free_count = FSINFO_UNKNOWN;
+            }
+            if ! self.is_valid_cluster(info.next_free) {
+                info.next_free = FSINFO_UNKNOWN;
+            }
+            info
+        }))
+    }
+
+    pub fn write_fsinfo(&mut self) -> Result<()> {
+        if let Some(info) = self.fsinfo {
+            let mut data = [0; 8];
+            set_le_u32(&mut data, 0, info.free_count);
+            set_le_u32(&mut data, 4, info.next_free);
+            let offset = self.fsinfo_offset() + 0x1E8;
+            self.write_at(offset, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Update the hints after `count` clusters were allocated, moving the
+    /// next free hint on to `next` if `advance` is set
+    pub fn fsinfo_allocated(&mut self, count: u32, next: u32, advance: bool) {
+        if let Some(ref mut info) = self.fsinfo {
+            if info.free_count != FSINFO_UNKNOWN {
+                info.free_count = info.free_count.saturating_sub(count);
+            }
+            if advance || info.next_free == FSINFO_UNKNOWN {
+                info.next_free = next;
+            }
+        }
+    }
+
+    /// Update the hints after `count` clusters were freed
+    pub fn fsinfo_freed(&mut self, count: u32) {
+        let clusters = self.bpb.cluster_count();
+        if let Some(ref mut info) = self.fsinfo {
+            if info.free_count != FSINFO_UNKNOWN {
+                info.free_count = min(info.free_count.saturating_add(count), clusters);
+            }
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+
+use syscall::error::{Error, Result, EBADF, ENOENT};
+
+use super::{Disk, FileSystem};
+use super::entry::{DirEntry, ENTRY_SIZE};
+use super::node::Node;
+
+/// Inode numbers of empty files, which have no start cluster, are their entry
+/// index on disk from here on, above any cluster number
+const INO_EMPTY_BASE: u64 = 0x1_0000_0000;
+
+/// Inode numbers handed out when the usual one is taken start here, above
+/// any entry index
+const INO_SPARE_BASE: u64 = 1 << 48;
+
+struct Inode {
+    /// Byte offset of the entry, following the node through renames
+    block: u64,
+    /// First cluster of the directory holding the entry
+    parent: u32,
+    /// Open handles using this inode
+    refs: usize,
+    /// The entry of a node removed while open, kept here as its slot on disk
+    /// may be reused. Its clusters are freed when the last handle closes.
+    orphan: Option<DirEntry>,
+}
+
+/// Inode numbers of the nodes with open handles. FAT has no inodes, so a node
+/// is numbered after its start cluster, or its entry when it has none, and
+/// keeps that number while it is open however its clusters or entry move.
+#[derive(Default)]
+pub struct InodeTable {
+    inodes: BTreeMap<u64, Inode>,
+    /// Inode number of the entry at each byte offset
+    blocks: BTreeMap<u64, u64>,
+    next_spare: u64,
+}
+
+impl<D: Disk> FileSystem<D> {
+    /// The number `node` is known by when it has no open handles
+    fn natural_ino(&self, node: &Node) -> u64 {
+        if node.entry.cluster != 0 {
+            node.entry.cluster as u64
+        } else {
+            INO_EMPTY_BASE + node.offset / ENTRY_SIZE as u64
+        }
+    }
+
+    /// Take a reference on the inode of `node` for a new handle, numbering it
+    /// if it has none open
+    pub fn open_inode(&mut self, node: &Node) -> u64 {
+        if let Some(&ino) = self.inodes.blocks.get(&node.offset) {
+            if let Some(inode) = self.inodes.inodes.get_mut(&ino) {
+                inode.refs += 1;
+            }
+            return ino;
+        }
+
+        let mut ino = self.natural_ino(node);
+        while self.inodes.inodes.contains_key(&ino) {
+            ino = INO_SPARE_BASE + self.inodes.next_spare;
+            self.inodes.next_spare += 1;
+        }
+
+        self.inodes.inodes.insert(ino, Inode {
+            block: node.offset,
+            parent: node.parent,
+            refs: 1,
+            orphan: None,
+        });
+        self.inodes.blocks.insert(node.offset, ino);
+        ino
+    }
+
+    /// Take another reference on `ino`, for a duplicated handle
+    pub fn dup_inode(&mut self, ino: u64) {
+        if let Some(inode) = self.inodes.inodes.get_mut(&ino) {
+            inode.refs += 1;
+        }
+    }
+
+    /// Drop a reference on `ino`, forgetting it once no handle uses it and
+    /// freeing the clusters of an orphan
+    pub fn close_inode(&mut self, ino: u64) -> Result<()> {
+        let last = match self.inodes.inodes.get_mut(&ino) {
+            Some(inode) => {
+                inode.refs -= 1;
+                inode.refs == 0
+            },
+            None => false
+        };
+        if ! last {
+            return Ok(());
+        }
+
+        let inode = match self.inodes.inodes.remove(&ino) {
+            Some(inode) => inode,
+            None => return Ok(())
+        };
+        match inode.orphan {
+            Some(entry) => if entry.cluster != 0 {
+                self.free_chain(entry.cluster)?;
+            },
+            None => if self.inodes.blocks.get(&inode.block) == Some(&ino) {
+                self.inodes.blocks.remove(&inode.block);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `ino` is the root directory, which has no entry of its own
+    pub fn inode_is_root(&self, ino: u64) -> Result<bool> {
+        match self.inodes.inodes.get(&ino) {
+            Some(inode) => Ok(inode.block == 0 && inode.orphan.is_none()),
+            None => Err(Error::new(EBADF))
+        }
+    }
+
+    /// The entry of the open inode `ino`
+    pub fn inode_entry(&mut self, ino: u64) -> Result<DirEntry> {
+        let block = match self.inodes.inodes.get(&ino) {
+            Some(&Inode { orphan: Some(entry), .. }) => return Ok(entry),
+            Some(inode) => inode.block,
+            None => return Err(Error::new(EBADF))
+        };
+        self.entry(block)
+    }
+
+    /// Replace the entry of the open inode `ino`
+    pub fn write_inode_entry(&mut self, ino: u64, entry: &DirEntry) -> Result<()> {
+        let block = match self.inodes.inodes.get_mut(&ino) {
+            Some(&mut Inode { orphan: Some(ref mut orphan), .. }) => {
+                *orphan = *entry;
+                return Ok(());
+            },
+            Some(inode) => inode.block,
+            None => return Err(Error::new(EBADF))
+        };
+        self.write_entry(block, entry)
+    }
+
+    /// The node of the open inode `ino`, found through where its entry is
+    /// rather than by name. ENOENT if it was removed.
+    pub fn inode_node(&mut self, ino: u64) -> Result<Node> {
+        let (block, parent) = match self.inodes.inodes.get(&ino) {
+            Some(&Inode { orphan: Some(_), .. }) => return Err(Error::new(ENOENT)),
+            Some(inode) => (inode.block, inode.parent),
+            None => return Err(Error::new(EBADF))
+        };
+        if block == 0 {
+            return Ok(self.root_node());
+        }
+
+        self.read_dir(parent)?.into_iter()
+            .find(|node| node.offset == block)
+            .ok_or(Error::new(ENOENT))
+    }
+
+    /// The entry at `from` now lives at `to`, in the directory starting at `parent`
+    pub fn inode_moved(&mut self, from: u64, to: u64, parent: u32) {
+        if let Some(ino) = self.inodes.blocks.remove(&from) {
+            if let Some(inode) = self.inodes.inodes.get_mut(&ino) {
+                inode.block = to;
+                inode.parent = parent;
+            }
+            self.inodes.blocks.insert(to, ino);
+        }
+    }
+
+    /// The entry `entry` at `block` was deleted, so a node created there
+    /// later is a different inode. If handles are still open on it, it lives
+    /// on as an orphan and true is returned, its clusters then being freed on
+    /// the last close rather than by the caller.
+    pub fn inode_removed(&mut self, block: u64, entry: &DirEntry) -> bool {
+        let ino = match self.inodes.blocks.remove(&block) {
+            Some(ino) => ino,
+            None => return false
+        };
+        match self.inodes.inodes.get_mut(&ino) {
+            Some(inode) => {
+                inode.orphan = Some(*entry);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{INO_EMPTY_BASE, INO_SPARE_BASE};
+    use super::super::entry::ENTRY_SIZE;
+    use super::super::extent::Extents;
+    use super::super::memdisk::{check, create_file, read_file, volume};
+    use syscall::error::ENOENT;
+
+    #[test]
+    fn unlink_while_open() {
+        let mut fs = volume(64, "");
+        let node = create_file(&mut fs, "a", &[1; 1000]);
+        let ino = fs.open_inode(&node);
+        fs.remove_file(&node).unwrap();
+        assert_eq!(fs.find_node("a").unwrap_err().errno, ENOENT);
+
+        // The clusters stay with the handle, so a new file cannot take them
+        create_file(&mut fs, "b", &[2; 600]);
+        let mut buf = vec![0; 1000];
+        assert_eq!(fs.read_node(ino, 0, &mut buf, &mut Extents::new()).unwrap(), 1000);
+        assert_eq!(buf, vec![1; 1000]);
+        assert_eq!(fs.write_node(ino, 1000, &[3; 100], &mut Extents::new()).unwrap(), 100);
+        assert_eq!(fs.node_len(ino).unwrap(), 1100);
+        assert_eq!(fs.free_clusters().unwrap(), 63 - 3 - 2);
+
+        fs.close_inode(ino).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), 63 - 2);
+        assert_eq!(read_file(&mut fs, "b"), vec![2; 600]);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn rmdir_while_open() {
+        let mut fs = volume(64, "");
+        let root = fs.root_node();
+        let dir = fs.create_dir(&root, "d").unwrap();
+        let ino = fs.open_inode(&dir);
+        let dup = fs.open_inode(&dir);
+        assert_eq!(ino, dup);
+        fs.remove_dir(&dir).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), 62);
+
+        fs.close_inode(ino).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), 62);
+        fs.close_inode(dup).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), 63);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn numbers_stay_while_open() {
+        let mut fs = volume(64, "");
+        let root = fs.root_node();
+        let full = create_file(&mut fs, "full", b"data");
+        let empty = create_file(&mut fs, "empty", b"");
+
+        let ino = fs.open_inode(&full);
+        assert_eq!(ino, full.entry.cluster as u64);
+        let empty_ino = fs.open_inode(&empty);
+        assert_eq!(empty_ino, INO_EMPTY_BASE + empty.offset / ENTRY_SIZE as u64);
+        assert_eq!(fs.open_inode(&empty), empty_ino);
+
+        // Moving and growing do not change the number, and the inode follows its entry
+        let docs = fs.create_dir(&root, "docs").unwrap();
+        fs.rename_node(&empty, &docs, "moved").unwrap();
+        fs.write_node(empty_ino, 0, b"now", &mut Extents::new()).unwrap();
+        assert_eq!(fs.inode_entry(empty_ino).unwrap().size, 3);
+        assert_eq!(fs.inode_node(empty_ino).unwrap().name, "moved");
+
+        fs.close_inode(empty_ino).unwrap();
+        assert_eq!(fs.inode_node(empty_ino).unwrap().name, "moved");
+        fs.close_inode(empty_ino).unwrap();
+        fs.close_inode(ino).unwrap();
+
+        // Opened afresh, it goes by its cluster
+        let moved = fs.find_node("docs/moved").unwrap();
+        let ino = fs.open_inode(&moved);
+        assert_eq!(ino, moved.entry.cluster as u64);
+        fs.close_inode(ino).unwrap();
+        assert_eq!(read_file(&mut fs, "docs/moved"), b"now");
+        check(&mut fs);
+    }
+
+    #[test]
+    fn spare_numbers_on_clashes() {
+        // An empty file removed while open keeps its number, so a new one in
+        // the same slot needs another
+        let mut fs = volume(64, "");
+        let old = create_file(&mut fs, "old", b"");
+        let ino = fs.open_inode(&old);
+        fs.remove_file(&old).unwrap();
+        let new = create_file(&mut fs, "new", b"");
+        assert_eq!(new.offset, old.offset);
+
+        let other = fs.open_inode(&new);
+        assert!(other >= INO_SPARE_BASE);
+        assert_eq!(fs.inode_node(ino).unwrap_err().errno, ENOENT);
+        assert_eq!(fs.inode_node(other).unwrap().name, "new");
+
+        fs.close_inode(ino).unwrap();
+        fs.close_inode(other).unwrap();
+        check(&mut fs);
+    }
+}
@@ -0,0 +1,184 @@
+//! Scratch FAT32 volumes kept in memory, and a consistency check of their
+//! FAT and directories, for the tests
+
+use std::io::{Cursor, Result as IoResult};
+
+use options::MountOptions;
+
+use super::{Disk, FileSystem, Node, ATTR_ARCHIVE, set_le_u16, set_le_u32};
+use super::entry::ENTRY_SIZE;
+use super::extent::Extents;
+use super::fsinfo::FSINFO_UNKNOWN;
+use super::table::{FAT_EOC, FAT_FREE};
+
+pub type MemFs = FileSystem<Cursor<Vec<u8>>>;
+
+impl Disk for Cursor<Vec<u8>> {
+    fn sync(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+const SECTOR_SIZE: usize = 512;
+const RESERVED_SECTORS: usize = 32;
+const FATS: usize = 2;
+
+/// A freshly formatted volume of `clusters` one sector clusters, with only
+/// the root directory in cluster 2, mounted with `options`
+pub fn volume(clusters: u32, options: &str) -> MemFs {
+    let fat_size = ((clusters as usize + 2) * 4 + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    let total = RESERVED_SECTORS + FATS * fat_size + clusters as usize;
+    let mut image = vec![0; total * SECTOR_SIZE];
+
+    {
+        let boot = &mut image[..SECTOR_SIZE];
+        boot[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        set_le_u16(boot, 0x0B, SECTOR_SIZE as u16);
+        boot[0x0D] = 1;
+        set_le_u16(boot, 0x0E, RESERVED_SECTORS as u16);
+        boot[0x10] = FATS as u8;
+        boot[0x15] = 0xF8;
+        set_le_u32(boot, 0x20, total as u32);
+        set_le_u32(boot, 0x24, fat_size as u32);
+        set_le_u32(boot, 0x2C, 2);
+        set_le_u16(boot, 0x30, 1);
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+    }
+
+    {
+        let info = &mut image[SECTOR_SIZE..2 * SECTOR_SIZE];
+        set_le_u32(info, 0, 0x4161_5252);
+        set_le_u32(info, 0x1E4, 0x6141_7272);
+        set_le_u32(info, 0x1E8, clusters - 1);
+        set_le_u32(info, 0x1EC, 3);
+        set_le_u32(info, 0x1FC, 0xAA55_0000);
+    }
+
+    for fat in 0..FATS {
+        let offset = (RESERVED_SECTORS + fat * fat_size) * SECTOR_SIZE;
+        let table = &mut image[offset..offset + 12];
+        set_le_u32(table, 0, 0x0FFF_FFF8);
+        set_le_u32(table, 4, 0x0FFF_FFFF);
+        set_le_u32(table, 8, FAT_EOC);
+    }
+
+    let options = MountOptions::parse(options).unwrap();
+    FileSystem::open(Cursor::new(image), options).unwrap()
+}
+
+/// Create the file `path` holding `data`
+pub fn create_file(fs: &mut MemFs, path: &str, data: &[u8]) -> Node {
+    let (parent, name) = match path.rfind('/') {
+        Some(i) => (fs.find_node(&path[..i]).unwrap(), &path[i + 1..]),
+        None => (fs.root_node(), path)
+    };
+    let entry = fs.new_entry(ATTR_ARCHIVE, 0);
+    let node = fs.create_node(&parent, name, entry).unwrap();
+
+    let ino = fs.open_inode(&node);
+    assert_eq!(fs.write_node(ino, 0, data, &mut Extents::new()).unwrap(), data.len());
+    fs.close_inode(ino).unwrap();
+
+    fs.find_node(path).unwrap()
+}
+
+/// The contents of the file `path`
+pub fn read_file(fs: &mut MemFs, path: &str) -> Vec<u8> {
+    let node = fs.find_node(path).unwrap();
+    let ino = fs.open_inode(&node);
+    let mut data = vec![0; node.entry.size as usize];
+    assert_eq!(fs.read_node(ino, 0, &mut data, &mut Extents::new()).unwrap(), data.len());
+    fs.close_inode(ino).unwrap();
+    data
+}
+
+/// The names in the directory `path`, in the order they are stored
+pub fn names(fs: &mut MemFs, path: &str) -> Vec<String> {
+    let dir = fs.find_node(path).unwrap();
+    fs.read_dir(dir.entry.cluster).unwrap().into_iter().map(|node| node.name).collect()
+}
+
+/// Check that every FAT copy is the same, that the clusters in use are
+/// exactly those reachable from the root directory, each once, that every
+/// chain is as long as its size calls for, that `.` and `..` point where
+/// they should, and that the free counts agree with the FAT
+pub fn check(fs: &mut MemFs) {
+    let fat_len = fs.bpb.fat_size as usize * fs.bpb.bytes_per_sector as usize;
+    {
+        let image = fs.disk.get_ref();
+        let first = fs.bpb.fat_offset(0) as usize;
+        for fat in 1..fs.bpb.fats {
+            let offset = fs.bpb.fat_offset(fat) as usize;
+            assert!(image[first..first + fat_len] == image[offset..offset + fat_len], "FAT {} differs", fat);
+        }
+    }
+
+    let end = fs.bpb.cluster_count() + 2;
+    let mut owners = vec![None; end as usize];
+    let root = fs.bpb.root_cluster;
+    claim(fs, &mut owners, root, "/");
+    check_dir(fs, &mut owners, root, 0, "");
+
+    let mut free = 0;
+    for cluster in 2..end {
+        let used = fs.fat_entry(cluster).unwrap() != FAT_FREE;
+        assert!(used == owners[cluster as usize].is_some(), "cluster {} is {} but {}", cluster,
+                if used { "used" } else { "free" },
+                if used { "not in any chain" } else { "in a chain" });
+        if ! used {
+            free += 1;
+        }
+    }
+
+    if let Some(info) = fs.fsinfo {
+        if info.free_count != FSINFO_UNKNOWN {
+            assert_eq!(info.free_count, free, "FSInfo free count");
+        }
+    }
+    if let Some(ref map) = fs.free_map {
+        assert_eq!(map.free(), free, "free map count");
+        for cluster in 2..end {
+            assert_eq!(map.is_free(cluster), owners[cluster as usize].is_none(), "free map cluster {}", cluster);
+        }
+    }
+}
+
+/// Record `path` as the owner of every cluster of the chain at `start`,
+/// returning how many there are
+fn claim(fs: &mut MemFs, owners: &mut Vec<Option<String>>, start: u32, path: &str) -> u64 {
+    let chain = fs.chain(start).unwrap();
+    for &cluster in chain.iter() {
+        if let Some(ref owner) = owners[cluster as usize] {
+            panic!("cluster {} is in both {} and {}", cluster, owner, path);
+        }
+        owners[cluster as usize] = Some(path.to_string());
+    }
+    chain.len() as u64
+}
+
+fn check_dir(fs: &mut MemFs, owners: &mut Vec<Option<String>>, cluster: u32, parent: u32, path: &str) {
+    let cluster_size = fs.bpb.cluster_size();
+    for node in fs.read_dir(cluster).unwrap() {
+        let child = format!("{}/{}", path, node.name);
+        let clusters = claim(fs, owners, node.entry.cluster, &child);
+
+        if node.is_dir() {
+            assert!(node.entry.cluster != 0, "{} has no cluster", child);
+            let offset = fs.bpb.cluster_offset(node.entry.cluster);
+            let dot = fs.entry(offset).unwrap();
+            let dotdot = fs.entry(offset + ENTRY_SIZE as u64).unwrap();
+            assert_eq!(&dot.name, b".          ", "{} has no . entry", child);
+            assert_eq!(dot.cluster, node.entry.cluster, "{}/. cluster", child);
+            assert_eq!(&dotdot.name, b"..         ", "{} has no .. entry", child);
+            assert_eq!(dotdot.cluster, parent, "{}/.. cluster", child);
+
+            let own = node.entry.cluster;
+            check_dir(fs, owners, own, own, &child);
+        } else {
+            let size = node.entry.size as u64;
+            assert_eq!(clusters, (size + cluster_size - 1) / cluster_size, "{} has {} bytes in {} clusters",
+                       child, size, clusters);
+        }
+    }
+}
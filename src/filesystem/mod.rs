@@ -0,0 +1,563 @@
+//! FAT32 on-disk structures and the operations the scheme needs on them
+
+use std::cmp::min;
+use std::io::SeekFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use syscall::error::{Error, Result, EFBIG, EINVAL, EIO, EPERM};
+use syscall::flag::{MODE_DIR, MODE_FILE};
+
+use options::{AtimePolicy, MountOptions};
+
+use self::bitmap::FreeMap;
+use self::dentry::DentryCache;
+use self::extent::ChainGenerations;
+use self::fsinfo::FSINFO_UNKNOWN;
+use self::inode::InodeTable;
+
+pub use self::bpb::BiosParameterBlock;
+pub use self::codepage::Codepage;
+pub use self::disk::Disk;
+pub use self::entry::*;
+pub use self::extent::Extents;
+pub use self::fsinfo::FsInfo;
+pub use self::name::{ShortnamePolicy, generate_short_name, validate_name};
+pub use self::node::Node;
+pub use self::time::FatTime;
+
+mod alloc;
+mod bitmap;
+mod bpb;
+mod codepage;
+mod dentry;
+mod dir;
+mod disk;
+mod entry;
+mod extent;
+mod fsinfo;
+mod inode;
+#[cfg(test)]
+pub mod memdisk;
+mod name;
+mod node;
+mod table;
+mod time;
+
+macro_rules! try_disk {
+    ($expr:expr) => (match $expr {
+        Ok(val) => val,
+        Err(err) => {
+            eprintln!("redox-fatd: disk error: {}", err);
+            return Err(Error::new(EIO));
+        }
+    })
+}
+
+/// Largest size a directory entry can record
+pub const MAX_FILE_SIZE: u64 = 0xFFFF_FFFF;
+
+/// Access bits checked by `permission`
+pub const PERM_EXEC: u16 = 0o1;
+pub const PERM_WRITE: u16 = 0o2;
+pub const PERM_READ: u16 = 0o4;
+
+/// Attributes that may be changed with `set_attributes`
+pub const ATTR_SETTABLE: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_ARCHIVE;
+
+pub fn le_u16(buf: &[u8], offset: usize) -> u16 {
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+pub fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    le_u16(buf, offset) as u32 | (le_u16(buf, offset + 2) as u32) << 16
+}
+
+pub fn set_le_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+}
+
+pub fn set_le_u32(buf: &mut [u8], offset: usize, value: u32) {
+    set_le_u16(buf, offset, value as u16);
+    set_le_u16(buf, offset + 2, (value >> 16) as u16);
+}
+
+pub struct FileSystem<D: Disk> {
+    disk: D,
+    pub bpb: BiosParameterBlock,
+    pub fsinfo: Option<FsInfo>,
+    pub options: MountOptions,
+    /// Which clusters are free, built on first use
+    free_map: Option<FreeMap>,
+    /// Which chains lost clusters, outdating their `Extents`
+    chain_generations: ChainGenerations,
+    /// Where the search for free clusters for directories and for files
+    /// starts, past the last ones allocated, whether or not there is FSInfo.
+    /// Directories start from the middle of the volume, out of the way of
+    /// file data, which starts from the FSInfo hint.
+    dir_goal: u32,
+    file_goal: u32,
+    /// Names recently looked up in directories
+    dentries: DentryCache,
+    /// Inode numbers of the nodes with open handles
+    inodes: InodeTable,
+}
+
+impl<D: Disk> FileSystem<D> {
+    pub fn open(mut disk: D, options: MountOptions) -> Result<FileSystem<D>> {
+        let mut sector = [0; 512];
+        try_disk!(disk.seek(SeekFrom::Start(0)));
+        try_disk!(disk.read_exact(&mut sector));
+        let bpb = BiosParameterBlock::parse(&sector)?;
+        let middle = 2 + bpb.cluster_count() / 2;
+
+        let mut fs = FileSystem {
+            disk: disk,
+            bpb: bpb,
+            fsinfo: None,
+            options: options,
+            free_map: None,
+            chain_generations: ChainGenerations::default(),
+            dir_goal: middle,
+            file_goal: 2,
+            dentries: DentryCache::default(),
+            inodes: InodeTable::default(),
+        };
+        fs.fsinfo = fs.read_fsinfo()?;
+        if let Some(info) = fs.fsinfo {
+            if info.next_free != FSINFO_UNKNOWN {
+                fs.file_goal = info.next_free;
+            }
+        }
+
+        Ok(fs)
+    }
+
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        try_disk!(self.disk.seek(SeekFrom::Start(offset)));
+        try_disk!(self.disk.read_exact(buf));
+        Ok(())
+    }
+
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        try_disk!(self.disk.seek(SeekFrom::Start(offset)));
+        try_disk!(self.disk.write_all(buf));
+        Ok(())
+    }
+
+    /// Sync everything written so far to the disk. Data, FAT and directory
+    /// entries are written through as they change, so nothing else is held back
+    pub fn sync(&mut self) -> Result<()> {
+        try_disk!(self.disk.sync());
+        Ok(())
+    }
+
+    /// The 8.3 entry stored at byte offset `block`, 0 being the root directory
+    pub fn entry(&mut self, block: u64) -> Result<DirEntry> {
+        if block == 0 {
+            return Ok(DirEntry::root(self.bpb.root_cluster));
+        }
+
+        let mut data = [0; ENTRY_SIZE];
+        self.read_at(block, &mut data)?;
+        Ok(DirEntry::parse(&data))
+    }
+
+    pub fn write_entry(&mut self, block: u64, entry: &DirEntry) -> Result<()> {
+        if block == 0 {
+            return Err(Error::new(EIO));
+        }
+
+        let mut data = [0; ENTRY_SIZE];
+        entry.serialize(&mut data);
+        self.write_at(block, &data)
+    }
+
+    pub fn node_len(&mut self, ino: u64) -> Result<u64> {
+        Ok(self.inode_entry(ino)?.size as u64)
+    }
+
+    /// Split `len` bytes at `offset` into the chain starting at `start` into
+    /// pieces of (disk offset, start, end) relative to `offset`
+    fn segments(&mut self, start: u32, offset: u64, len: usize, extents: &mut Extents) -> Result<Vec<(u64, usize, usize)>> {
+        let mut segments = Vec::new();
+        if len == 0 {
+            return Ok(segments);
+        }
+
+        let cluster_size = self.bpb.cluster_size();
+        let mut i = 0;
+        while i < len {
+            let position = offset + i as u64;
+            let cluster = self.extent_cluster(extents, start, position / cluster_size)?;
+            let in_cluster = position % cluster_size;
+            let count = min((cluster_size - in_cluster) as usize, len - i);
+            segments.push((self.bpb.cluster_offset(cluster) + in_cluster, i, i + count));
+            i += count;
+        }
+
+        Ok(segments)
+    }
+
+    /// Read the contents of the file open as inode `ino`, starting at
+    /// `offset`, looking its clusters up in `extents`
+    pub fn read_node(&mut self, ino: u64, offset: u64, buf: &mut [u8], extents: &mut Extents) -> Result<usize> {
+        let mut entry = self.inode_entry(ino)?;
+        let size = entry.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = min(buf.len() as u64, size - offset) as usize;
+        for (disk_offset, start, end) in self.segments(entry.cluster, offset, len, extents)? {
+            self.read_at(disk_offset, &mut buf[start..end])?;
+        }
+
+        if self.options.atime == AtimePolicy::RelAtime {
+            let now = self.now();
+            if entry.adate != now.date {
+                entry.set_accessed(now);
+                self.write_inode_entry(ino, &entry)?;
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// Write zeroes over `from..to` of the chain starting at `start`
+    fn zero_range(&mut self, start: u32, from: u64, to: u64, extents: &mut Extents) -> Result<()> {
+        let zeroes = vec![0; self.bpb.cluster_size() as usize];
+        for (disk_offset, begin, end) in self.segments(start, from, (to - from) as usize, extents)? {
+            self.write_at(disk_offset, &zeroes[..end - begin])?;
+        }
+        Ok(())
+    }
+
+    /// Write to the file open as inode `ino`, growing it as needed. A
+    /// gap between the old end of file and `offset` reads back as zeroes.
+    /// Only the part of `buf` below `MAX_FILE_SIZE` is written, EFBIG is
+    /// returned when none of it is
+    pub fn write_node(&mut self, ino: u64, offset: u64, buf: &[u8], extents: &mut Extents) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if offset >= MAX_FILE_SIZE {
+            return Err(Error::new(EFBIG));
+        }
+
+        let mut buf = &buf[..min(buf.len() as u64, MAX_FILE_SIZE - offset) as usize];
+
+        let mut entry = self.inode_entry(ino)?;
+        let size = entry.size as u64;
+        if let Err(err) = self.ensure_clusters(&mut entry, offset + buf.len() as u64, extents) {
+            // Write what fits in the clusters that could be allocated, giving
+            // them back if that is nothing
+            let capacity = self.extent_end(extents, entry.cluster)?.0 * self.bpb.cluster_size();
+            if capacity <= offset {
+                self.shrink_clusters(&mut entry, size, extents)?;
+                self.write_inode_entry(ino, &entry)?;
+                return Err(err);
+            }
+            buf = &buf[..(capacity - offset) as usize];
+        }
+        let end = offset + buf.len() as u64;
+
+        if offset > size {
+            self.zero_range(entry.cluster, size, offset, extents)?;
+        }
+
+        for (disk_offset, start, end) in self.segments(entry.cluster, offset, buf.len(), extents)? {
+            self.write_at(disk_offset, &buf[start..end])?;
+        }
+
+        if end > size {
+            entry.size = end as u32;
+        }
+        entry.set_modified(self.now());
+        entry.attr |= ATTR_ARCHIVE;
+        self.write_inode_entry(ino, &entry)?;
+
+        Ok(buf.len())
+    }
+
+    /// Shrink or grow the file open as inode `ino` to `len` bytes,
+    /// freeing clusters past the new end or zero filling the new range
+    pub fn node_set_len(&mut self, ino: u64, len: u64, extents: &mut Extents) -> Result<()> {
+        if len > MAX_FILE_SIZE {
+            return Err(Error::new(EFBIG));
+        }
+
+        let mut entry = self.inode_entry(ino)?;
+        let size = entry.size as u64;
+        if len < size {
+            self.shrink_clusters(&mut entry, len, extents)?;
+        } else if len > size {
+            if let Err(err) = self.ensure_clusters(&mut entry, len, extents) {
+                self.shrink_clusters(&mut entry, size, extents)?;
+                self.write_inode_entry(ino, &entry)?;
+                return Err(err);
+            }
+            self.zero_range(entry.cluster, size, len, extents)?;
+        }
+
+        entry.size = len as u32;
+        entry.set_modified(self.now());
+        entry.attr |= ATTR_ARCHIVE;
+        self.write_inode_entry(ino, &entry)
+    }
+
+    /// The attribute byte of the open inode `ino`
+    pub fn attributes(&mut self, ino: u64) -> Result<u8> {
+        Ok(self.inode_entry(ino)?.attr)
+    }
+
+    /// Replace the settable attributes of the open inode `ino` with `attr`,
+    /// keeping the DIRECTORY and VOLUME_ID bits as they are
+    pub fn set_attributes(&mut self, ino: u64, attr: u8) -> Result<()> {
+        if attr & ! ATTR_SETTABLE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        if self.inode_is_root(ino)? {
+            // The root directory has no entry to keep attributes in
+            return Err(Error::new(EPERM));
+        }
+
+        let mut entry = self.inode_entry(ino)?;
+        entry.attr = (entry.attr & ! ATTR_SETTABLE) | attr;
+        self.write_inode_entry(ino, &entry)
+    }
+
+    /// Set or clear the READ_ONLY attribute of the open inode `ino`
+    pub fn set_read_only(&mut self, ino: u64, read_only: bool) -> Result<()> {
+        let attr = self.attributes(ino)? & ATTR_SETTABLE;
+        if read_only {
+            self.set_attributes(ino, attr | ATTR_READ_ONLY)
+        } else {
+            self.set_attributes(ino, attr & ! ATTR_READ_ONLY)
+        }
+    }
+
+    /// Type and permission bits of `entry`, from the masks given at mount time
+    pub fn mode(&self, entry: &DirEntry) -> u16 {
+        if entry.is_dir() {
+            // Windows marks customized folders READ_ONLY, so it does not keep
+            // anyone from changing a directory
+            MODE_DIR | (0o777 & ! self.options.dmask)
+        } else {
+            // The only permission FAT records is whether the file may be written
+            let perm = if entry.is_read_only() { 0o555 } else { 0o777 };
+            MODE_FILE | (perm & ! self.options.fmask)
+        }
+    }
+
+    /// Whether `uid` and `gid` may access `entry` in every way set in `op`,
+    /// root always being allowed
+    pub fn permission(&self, entry: &DirEntry, uid: u32, gid: u32, op: u16) -> bool {
+        if uid == 0 {
+            return true;
+        }
+
+        let mode = self.mode(entry);
+        let perm = if uid == self.options.uid {
+            mode >> 6
+        } else if gid == self.options.gid {
+            mode >> 3
+        } else {
+            mode
+        };
+        perm & op == op
+    }
+
+    /// The current time, as it would be recorded in an entry
+    pub fn now(&self) -> FatTime {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => self.fat_time(duration.as_secs() as i64, duration.subsec_nanos()),
+            Err(_) => self.fat_time(0, 0)
+        }
+    }
+
+    /// An entry with attributes `attr` starting at `cluster`, created,
+    /// modified and accessed now
+    pub fn new_entry(&self, attr: u8, cluster: u32) -> DirEntry {
+        let now = self.now();
+        let mut entry = DirEntry {
+            attr: attr,
+            cluster: cluster,
+            ..Default::default()
+        };
+        entry.set_created(now);
+        entry.set_modified(now);
+        entry.set_accessed(now);
+        entry
+    }
+
+    /// Encode a Unix time as the local time given by the `time_offset` mount option
+    pub fn fat_time(&self, secs: i64, nsec: u32) -> FatTime {
+        FatTime::from_unix(secs + self.options.time_offset as i64 * 60, nsec)
+    }
+
+    /// Decode a local time recorded under the `time_offset` mount option into Unix time
+    pub fn unix_time(&self, time: FatTime) -> Option<(i64, u32)> {
+        time.to_unix().map(|(secs, nsec)| (secs - self.options.time_offset as i64 * 60, nsec))
+    }
+
+    /// Set the access and modification times of the open inode `ino`, the
+    /// access time keeping only its date
+    pub fn set_times(&mut self, ino: u64, atime: Option<FatTime>, mtime: Option<FatTime>) -> Result<()> {
+        if self.inode_is_root(ino)? {
+            // The root directory has no entry to keep times in
+            return Err(Error::new(EPERM));
+        }
+
+        let mut entry = self.inode_entry(ino)?;
+        if let Some(atime) = atime {
+            entry.set_accessed(atime);
+        }
+        if let Some(mtime) = mtime {
+            entry.set_modified(mtime);
+        }
+        self.write_inode_entry(ino, &entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::memdisk::{check, create_file, read_file, volume};
+    use syscall::error::ENOSPC;
+
+    #[test]
+    fn set_len_frees_and_zero_fills() {
+        let mut fs = volume(64, "");
+        let node = create_file(&mut fs, "f", &[9; 1200]);
+        let ino = fs.open_inode(&node);
+        let mut extents = Extents::new();
+
+        fs.node_set_len(ino, 100, &mut extents).unwrap();
+        assert_eq!(fs.chain(node.entry.cluster).unwrap().len(), 1);
+        assert_eq!(fs.free_clusters().unwrap(), 62);
+
+        // What the file held past its shrunk end must not come back
+        fs.node_set_len(ino, 2000, &mut extents).unwrap();
+        fs.close_inode(ino).unwrap();
+        let data = read_file(&mut fs, "f");
+        assert_eq!(data.len(), 2000);
+        assert_eq!(&data[..100], &[9; 100][..]);
+        assert!(data[100..].iter().all(|&byte| byte == 0));
+        assert_eq!(fs.free_clusters().unwrap(), 59);
+        check(&mut fs);
+
+        let ino = fs.open_inode(&node);
+        fs.node_set_len(ino, 0, &mut Extents::new()).unwrap();
+        fs.close_inode(ino).unwrap();
+        assert_eq!(fs.find_node("f").unwrap().entry.cluster, 0);
+        assert_eq!(fs.free_clusters().unwrap(), 63);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn write_past_end_zero_fills_gap() {
+        let mut fs = volume(64, "");
+        let node = create_file(&mut fs, "f", &[9; 10]);
+        let ino = fs.open_inode(&node);
+        assert_eq!(fs.write_node(ino, 1500, b"end", &mut Extents::new()).unwrap(), 3);
+        fs.close_inode(ino).unwrap();
+
+        let data = read_file(&mut fs, "f");
+        assert_eq!(data.len(), 1503);
+        assert_eq!(&data[..10], &[9; 10][..]);
+        assert!(data[10..1500].iter().all(|&byte| byte == 0));
+        assert_eq!(&data[1500..], b"end");
+        check(&mut fs);
+    }
+
+    #[test]
+    fn sizes_stop_at_the_limit() {
+        let mut fs = volume(64, "");
+        let node = create_file(&mut fs, "f", b"data");
+        let ino = fs.open_inode(&node);
+        let mut extents = Extents::new();
+
+        assert_eq!(fs.write_node(ino, MAX_FILE_SIZE, b"x", &mut extents).unwrap_err().errno, EFBIG);
+        assert_eq!(fs.node_set_len(ino, MAX_FILE_SIZE + 1, &mut extents).unwrap_err().errno, EFBIG);
+        // Within the limit but not on this volume
+        assert_eq!(fs.node_set_len(ino, MAX_FILE_SIZE, &mut extents).unwrap_err().errno, ENOSPC);
+        fs.close_inode(ino).unwrap();
+
+        assert_eq!(read_file(&mut fs, "f"), b"data");
+        assert_eq!(fs.free_clusters().unwrap(), 62);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn write_fills_what_space_is_left() {
+        let mut fs = volume(64, "");
+        let node = create_file(&mut fs, "f", b"");
+        let ino = fs.open_inode(&node);
+        let mut extents = Extents::new();
+
+        let data = vec![5; 100 * 512];
+        assert_eq!(fs.write_node(ino, 0, &data, &mut extents).unwrap(), 63 * 512);
+        assert_eq!(fs.write_node(ino, 63 * 512, &data, &mut extents).unwrap_err().errno, ENOSPC);
+        assert_eq!(fs.node_len(ino).unwrap(), 63 * 512);
+        fs.close_inode(ino).unwrap();
+
+        assert_eq!(read_file(&mut fs, "f"), &data[..63 * 512]);
+        assert_eq!(fs.free_clusters().unwrap(), 0);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn directories_stay_out_of_file_chains() {
+        let mut fs = volume(64, "");
+        let node = create_file(&mut fs, "f", &[1; 3 * 512]);
+        let root = fs.root_node();
+        let dir = fs.create_dir(&root, "d").unwrap();
+        assert!(dir.entry.cluster >= 2 + 64 / 2);
+        // Enough names to grow d past its first cluster
+        for i in 0..20 {
+            create_file(&mut fs, &format!("d/{}", i), b"");
+        }
+
+        let ino = fs.open_inode(&node);
+        fs.write_node(ino, 3 * 512, &[2; 3 * 512], &mut Extents::new()).unwrap();
+        fs.close_inode(ino).unwrap();
+
+        let chain = fs.chain(node.entry.cluster).unwrap();
+        assert_eq!(chain.len(), 6);
+        for pair in chain.windows(2) {
+            assert_eq!(pair[1], pair[0] + 1, "chain {:?} is broken up", chain);
+        }
+        check(&mut fs);
+    }
+
+    #[test]
+    fn permission_by_owner_group_and_others() {
+        let fs = volume(64, "uid=1000,gid=100,fmask=027,dmask=002");
+        let file = fs.new_entry(ATTR_ARCHIVE, 0);
+        let dir = fs.new_entry(ATTR_DIRECTORY, 0);
+        assert_eq!(fs.mode(&file), MODE_FILE | 0o750);
+        assert_eq!(fs.mode(&dir), MODE_DIR | 0o775);
+
+        let all = PERM_READ | PERM_WRITE | PERM_EXEC;
+        assert!(fs.permission(&file, 1000, 1, all));
+        assert!(fs.permission(&file, 2000, 100, PERM_READ | PERM_EXEC));
+        assert!(! fs.permission(&file, 2000, 100, PERM_WRITE));
+        assert!(! fs.permission(&file, 2000, 1, PERM_READ));
+        assert!(fs.permission(&file, 0, 0, all));
+        assert!(fs.permission(&dir, 2000, 100, all));
+        assert!(! fs.permission(&dir, 2000, 1, PERM_WRITE));
+        assert!(fs.permission(&dir, 2000, 1, PERM_READ | PERM_EXEC));
+
+        let read_only = DirEntry { attr: ATTR_ARCHIVE | ATTR_READ_ONLY, ..file };
+        assert_eq!(fs.mode(&read_only), MODE_FILE | 0o550);
+        assert!(! fs.permission(&read_only, 1000, 100, PERM_WRITE));
+        assert!(fs.permission(&read_only, 1000, 100, PERM_READ));
+        assert!(fs.permission(&read_only, 0, 0, PERM_WRITE));
+
+        // Windows sets READ_ONLY on customized folders, which stay writable
+        let read_only = DirEntry { attr: ATTR_DIRECTORY | ATTR_READ_ONLY, ..dir };
+        assert_eq!(fs.mode(&read_only), MODE_DIR | 0o775);
+        assert!(fs.permission(&read_only, 1000, 100, PERM_WRITE));
+    }
+}
@@ -0,0 +1,361 @@
+use std::cmp::min;
+use std::str::FromStr;
+
+use syscall::error::{Error, Result, EINVAL, ENAMETOOLONG};
+
+use super::codepage::Codepage;
+use super::entry::{LCASE_BASE, LCASE_EXT};
+
+/// How 8.3 names are presented and when new names get a long name,
+/// following the `shortname=` option of the Linux vfat driver
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShortnamePolicy {
+    /// Display short names in lowercase, store a long name unless the name is all uppercase
+    Lower,
+    /// Display short names as stored, store a long name unless the name is all uppercase
+    Win95,
+    /// Display short names using the NT case flags, store a long name unless
+    /// the base and the extension are each entirely lowercase or uppercase
+    WinNt,
+    /// Display short names using the NT case flags, store a long name unless the name is all uppercase
+    Mixed,
+}
+
+impl Default for ShortnamePolicy {
+    fn default() -> ShortnamePolicy {
+        ShortnamePolicy::Mixed
+    }
+}
+
+impl FromStr for ShortnamePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<ShortnamePolicy, String> {
+        match s {
+            "lower" => Ok(ShortnamePolicy::Lower),
+            "win95" => Ok(ShortnamePolicy::Win95),
+            "winnt" => Ok(ShortnamePolicy::WinNt),
+            "mixed" => Ok(ShortnamePolicy::Mixed),
+            _ => Err(format!("invalid shortname policy '{}'", s))
+        }
+    }
+}
+
+impl ShortnamePolicy {
+    /// Name presented for an entry that has no long name
+    pub fn display(&self, short: &[u8; 11], lcase: u8, codepage: Codepage) -> String {
+        let (lower_base, lower_ext) = match *self {
+            ShortnamePolicy::Lower => (true, true),
+            ShortnamePolicy::Win95 => (false, false),
+            ShortnamePolicy::WinNt | ShortnamePolicy::Mixed => (lcase & LCASE_BASE != 0, lcase & LCASE_EXT != 0),
+        };
+
+        let mut name = decode_part(&short[..8], lower_base, codepage);
+        let ext = decode_part(&short[8..], lower_ext, codepage);
+        if ! ext.is_empty() {
+            name.push('.');
+            name.push_str(&ext);
+        }
+        name
+    }
+
+    /// If `name` can be stored as an 8.3 entry alone under this policy,
+    /// return that entry's name and case flags
+    pub fn short_only(&self, name: &str, codepage: Codepage) -> Option<([u8; 11], u8)> {
+        let (base, ext) = match name.rfind('.') {
+            Some(dot) => (&name[..dot], &name[dot + 1..]),
+            None => (name, "")
+        };
+
+        if base.is_empty() || base.contains('.') {
+            return None;
+        }
+
+        let base_bytes = base.chars().map(|c| short_byte(c, codepage)).collect::<Option<Vec<u8>>>()?;
+        let ext_bytes = ext.chars().map(|c| short_byte(c, codepage)).collect::<Option<Vec<u8>>>()?;
+        if base_bytes.len() > 8 || ext_bytes.len() > 3 {
+            return None;
+        }
+
+        let base_case = letter_case(base);
+        let ext_case = letter_case(ext);
+        let lcase = match *self {
+            ShortnamePolicy::WinNt => {
+                let mut lcase = 0;
+                match base_case {
+                    LetterCase::Mixed => return None,
+                    LetterCase::Lower => lcase |= LCASE_BASE,
+                    LetterCase::Upper | LetterCase::None => ()
+                }
+                match ext_case {
+                    LetterCase::Mixed => return None,
+                    LetterCase::Lower => lcase |= LCASE_EXT,
+                    LetterCase::Upper | LetterCase::None => ()
+                }
+                lcase
+            },
+            ShortnamePolicy::Lower | ShortnamePolicy::Win95 | ShortnamePolicy::Mixed => {
+                match (base_case, ext_case) {
+                    (LetterCase::Upper, LetterCase::Upper) | (LetterCase::Upper, LetterCase::None)
+                    | (LetterCase::None, LetterCase::Upper) | (LetterCase::None, LetterCase::None) => 0,
+                    _ => return None
+                }
+            }
+        };
+
+        let mut short = [b' '; 11];
+        short[..base_bytes.len()].copy_from_slice(&base_bytes);
+        short[8..8 + ext_bytes.len()].copy_from_slice(&ext_bytes);
+        Some((short, lcase))
+    }
+}
+
+/// Longest name an LFN sequence can hold, in UTF-16 code units
+pub const NAME_MAX: usize = 255;
+
+/// Names Windows reserves for devices, with or without an extension
+const DEVICE_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Check a name about to be created against FAT and Windows rules, so the
+/// volume stays readable elsewhere
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Error::new(EINVAL));
+    }
+
+    if name.encode_utf16().count() > NAME_MAX {
+        return Err(Error::new(ENAMETOOLONG));
+    }
+
+    if name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) {
+        return Err(Error::new(EINVAL));
+    }
+
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Err(Error::new(EINVAL));
+    }
+
+    let base = name.split('.').next().unwrap_or("").trim_right_matches(' ');
+    if DEVICE_NAMES.iter().any(|device| device.eq_ignore_ascii_case(base)) {
+        return Err(Error::new(EINVAL));
+    }
+
+    Ok(())
+}
+
+/// Derive an 8.3 name for a name that needs a long name, adding a numeric
+/// tail such as `~1` whenever the name does not fit as is or `exists`
+/// reports the plain form as taken
+pub fn generate_short_name<F: Fn(&[u8; 11]) -> bool>(name: &str, codepage: Codepage, exists: F) -> Option<[u8; 11]> {
+    let stripped: String = name.chars().filter(|&c| c != ' ').collect();
+    let stripped = stripped.trim_left_matches('.');
+    let mut lossy = stripped.len() != name.len();
+
+    let (base, ext) = match stripped.rfind('.') {
+        Some(dot) => (&stripped[..dot], &stripped[dot + 1..]),
+        None => (stripped, "")
+    };
+
+    let mut base = basis_part(base, 8, codepage, &mut lossy);
+    let ext = basis_part(ext, 3, codepage, &mut lossy);
+    if base.is_empty() {
+        base.push(b'_');
+        lossy = true;
+    }
+
+    let mut short = [b' '; 11];
+    short[8..8 + ext.len()].copy_from_slice(&ext);
+
+    if ! lossy {
+        short[..base.len()].copy_from_slice(&base);
+        if ! exists(&short) {
+            return Some(short);
+        }
+    }
+
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let keep = min(base.len(), 8 - tail.len());
+        for b in short[..8].iter_mut() {
+            *b = b' ';
+        }
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if ! exists(&short) {
+            return Some(short);
+        }
+    }
+
+    None
+}
+
+/// Up to `max` 8.3 bytes for one part of a basis name, with periods dropped
+/// and characters that cannot be stored replaced by `_`
+fn basis_part(part: &str, max: usize, codepage: Codepage, lossy: &mut bool) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for c in part.chars() {
+        if c == '.' {
+            *lossy = true;
+            continue;
+        }
+        if bytes.len() == max {
+            *lossy = true;
+            break;
+        }
+
+        let mut upper = c.to_uppercase();
+        let b = match (upper.next(), upper.next()) {
+            (Some(u), None) => short_byte(u, codepage),
+            _ => None
+        };
+        bytes.push(b.unwrap_or_else(|| {
+            *lossy = true;
+            b'_'
+        }));
+    }
+    bytes
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LetterCase {
+    None,
+    Lower,
+    Upper,
+    Mixed,
+}
+
+fn letter_case(part: &str) -> LetterCase {
+    let lower = part.chars().any(|c| c.is_ascii_lowercase());
+    let upper = part.chars().any(|c| c.is_ascii_uppercase());
+    match (lower, upper) {
+        (false, false) => LetterCase::None,
+        (true, false) => LetterCase::Lower,
+        (false, true) => LetterCase::Upper,
+        (true, true) => LetterCase::Mixed,
+    }
+}
+
+/// The uppercased 8.3 byte for `c`, if it may appear in an 8.3 name.
+/// Case flags only cover ASCII letters, so other characters are only
+/// accepted when they have no distinct uppercase form
+fn short_byte(c: char, codepage: Codepage) -> Option<u8> {
+    if c.is_ascii() {
+        if c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c) {
+            Some(c.to_ascii_uppercase() as u8)
+        } else {
+            None
+        }
+    } else {
+        let mut upper = c.to_uppercase();
+        match (upper.next(), upper.next()) {
+            (Some(u), None) if u == c => codepage.encode(c),
+            _ => None
+        }
+    }
+}
+
+fn decode_part(part: &[u8], lower: bool, codepage: Codepage) -> String {
+    let len = part.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    part[..len].iter().map(|&b| {
+        let c = codepage.decode(b);
+        if lower {
+            c.to_ascii_lowercase()
+        } else {
+            c
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn short(name: &str) -> [u8; 11] {
+        let mut short = [b' '; 11];
+        short[..name.len()].copy_from_slice(name.as_bytes());
+        short
+    }
+
+    #[test]
+    fn validate_name_rules() {
+        assert!(validate_name("readme.txt").is_ok());
+        assert!(validate_name("Long name with spaces.tar.gz").is_ok());
+        assert!(validate_name(".hidden").is_ok());
+        assert!(validate_name("console").is_ok());
+
+        for name in &["", ".", "..", "a*b", "a?b", "a:b", "a\\b", "a|b", "a<b", "a\"b", "a\x01b",
+                      "trailing.", "trailing ", "CON", "con.txt", "Lpt1", "nul .txt"] {
+            assert_eq!(validate_name(name).map_err(|err| err.errno), Err(EINVAL), "{:?}", name);
+        }
+
+        let long: String = ::std::iter::repeat('a').take(NAME_MAX).collect();
+        assert!(validate_name(&long).is_ok());
+        assert_eq!(validate_name(&format!("{}a", long)).map_err(|err| err.errno), Err(ENAMETOOLONG));
+    }
+
+    #[test]
+    fn generate_short_name_plain() {
+        assert_eq!(generate_short_name("Makefile", Codepage::Cp437, |_| false), Some(short("MAKEFILE")));
+        assert_eq!(generate_short_name("read.me", Codepage::Cp437, |_| false), Some(short("READ    ME")));
+    }
+
+    #[test]
+    fn generate_short_name_tails() {
+        assert_eq!(generate_short_name("Program Files", Codepage::Cp437, |_| false), Some(short("PROGRA~1")));
+        assert_eq!(generate_short_name("archive.tar.gz", Codepage::Cp437, |_| false), Some(short("ARCHIV~1GZ")));
+        assert_eq!(generate_short_name(".profile", Codepage::Cp437, |_| false), Some(short("PROFIL~1")));
+        assert_eq!(generate_short_name("a+b.txt", Codepage::Cp437, |_| false), Some(short("A_B~1   TXT")));
+
+        let taken = [short("PROGRA~1"), short("PROGRA~2")];
+        let name = generate_short_name("Program Files", Codepage::Cp437, |s| taken.contains(s));
+        assert_eq!(name, Some(short("PROGRA~3")));
+
+        let taken = [short("MAKEFILE")];
+        let name = generate_short_name("makefile", Codepage::Cp437, |s| taken.contains(s));
+        assert_eq!(name, Some(short("MAKEFI~1")));
+    }
+
+    #[test]
+    fn generate_short_name_long_tails() {
+        let name = generate_short_name("Program Files", Codepage::Cp437, |s| &s[..8] != b"PROGR~10");
+        assert_eq!(name, Some(short("PROGR~10")));
+    }
+
+    #[test]
+    fn short_only_case() {
+        let policy = ShortnamePolicy::Mixed;
+        assert_eq!(policy.short_only("README.TXT", Codepage::Cp437), Some((short("README  TXT"), 0)));
+        assert_eq!(policy.short_only("readme.txt", Codepage::Cp437), None);
+        assert_eq!(policy.short_only("123", Codepage::Cp437), Some((short("123"), 0)));
+
+        let policy = ShortnamePolicy::WinNt;
+        assert_eq!(policy.short_only("readme.TXT", Codepage::Cp437), Some((short("README  TXT"), LCASE_BASE)));
+        assert_eq!(policy.short_only("README.txt", Codepage::Cp437), Some((short("README  TXT"), LCASE_EXT)));
+        assert_eq!(policy.short_only("ReadMe.txt", Codepage::Cp437), None);
+    }
+
+    #[test]
+    fn short_only_limits() {
+        let policy = ShortnamePolicy::Win95;
+        assert_eq!(policy.short_only("ABCDEFGHI", Codepage::Cp437), None);
+        assert_eq!(policy.short_only("A.BCDE", Codepage::Cp437), None);
+        assert_eq!(policy.short_only("A.B.C", Codepage::Cp437), None);
+        assert_eq!(policy.short_only(".A", Codepage::Cp437), None);
+        assert_eq!(policy.short_only("A B", Codepage::Cp437), None);
+        assert_eq!(policy.short_only("A+B", Codepage::Cp437), None);
+    }
+
+    #[test]
+    fn display_policies() {
+        let name = short("README  TXT");
+        assert_eq!(ShortnamePolicy::Win95.display(&name, LCASE_BASE, Codepage::Cp437), "README.TXT");
+        assert_eq!(ShortnamePolicy::Lower.display(&name, 0, Codepage::Cp437), "readme.txt");
+        assert_eq!(ShortnamePolicy::Mixed.display(&name, LCASE_BASE, Codepage::Cp437), "readme.TXT");
+        assert_eq!(ShortnamePolicy::WinNt.display(&name, LCASE_EXT, Codepage::Cp437), "README.txt");
+        assert_eq!(ShortnamePolicy::Mixed.display(&short("MAKEFILE"), 0, Codepage::Cp437), "MAKEFILE");
+    }
+}
@@ -0,0 +1,44 @@
+use super::entry::DirEntry;
+
+/// A directory entry together with its name and where it lives on disk
+#[derive(Clone, Debug)]
+pub struct Node {
+    /// Long name if there is one, otherwise the 8.3 name as presented by the mount options
+    pub name: String,
+    /// The 8.3 name in its canonical uppercase form, e.g. `PROGRA~1.TXT`
+    pub short_name: String,
+    pub entry: DirEntry,
+    /// Byte offset of the 8.3 entry on disk, 0 for the root directory
+    pub offset: u64,
+    /// Byte offsets of the LFN entries belonging to this node
+    pub lfn_offsets: Vec<u64>,
+    /// First cluster of the directory containing this node
+    pub parent: u32,
+}
+
+impl Node {
+    pub fn root(cluster: u32) -> Node {
+        Node {
+            name: String::new(),
+            short_name: String::new(),
+            entry: DirEntry::root(cluster),
+            offset: 0,
+            lfn_offsets: Vec::new(),
+            parent: cluster,
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.offset == 0
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.entry.is_dir()
+    }
+
+    /// Whether `name` refers to this node, FAT names being case insensitive
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.name.to_lowercase() == name || self.short_name.to_lowercase() == name
+    }
+}
@@ -0,0 +1,134 @@
+use std::cmp::min;
+
+use syscall::error::{Error, Result, EIO};
+
+use super::{Disk, FileSystem, le_u32, set_le_u32};
+
+pub const FAT_FREE: u32 = 0;
+/// Entries at or above this value mark the end of a cluster chain
+pub const FAT_EOC_MIN: u32 = 0x0FFF_FFF8;
+/// Value written to terminate a chain
+pub const FAT_EOC: u32 = 0x0FFF_FFFF;
+/// Only the low 28 bits of a FAT32 entry are used
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+
+impl<D: Disk> FileSystem<D> {
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.bpb.cluster_count() + 2
+    }
+
+    /// Read the entry for `cluster` from the active FAT
+    pub fn fat_entry(&mut self, cluster: u32) -> Result<u32> {
+        if ! self.is_valid_cluster(cluster) {
+            return Err(Error::new(EIO));
+        }
+
+        let offset = self.bpb.fat_offset(self.bpb.active_fat()) + cluster as u64 * 4;
+        let mut buf = [0; 4];
+        self.read_at(offset, &mut buf)?;
+        Ok(le_u32(&buf, 0) & FAT_MASK)
+    }
+
+    /// Write the entry for `cluster` to every FAT copy in use, keeping the
+    /// reserved top four bits of each
+    pub fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<()> {
+        self.set_fat_entries(cluster, &[value])
+    }
+
+    /// Write the entries for the clusters from `first` on to every FAT copy
+    /// in use, one read and one write for each sector they span
+    pub fn set_fat_entries(&mut self, first: u32, values: &[u32]) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let last = first + values.len() as u32 - 1;
+        if ! self.is_valid_cluster(first) || ! self.is_valid_cluster(last) {
+            return Err(Error::new(EIO));
+        }
+
+        let fats = if self.bpb.mirroring() {
+            (0..self.bpb.fats).collect()
+        } else {
+            vec![self.bpb.active_fat()]
+        };
+
+        let sector_size = self.bpb.bytes_per_sector as u64;
+        for fat in fats {
+            let mut index = 0;
+            while index < values.len() {
+                let offset = self.bpb.fat_offset(fat) + (first as u64 + index as u64) * 4;
+                let count = min(((sector_size - offset % sector_size) / 4) as usize, values.len() - index);
+
+                let mut buf = vec![0; count * 4];
+                self.read_at(offset, &mut buf)?;
+                for (i, &value) in values[index..index + count].iter().enumerate() {
+                    let old = le_u32(&buf, i * 4);
+                    set_le_u32(&mut buf, i * 4, (old & !FAT_MASK) | (value & FAT_MASK));
+                }
+                self.write_at(offset, &buf)?;
+
+                index += count;
+            }
+        }
+
+        if let Some(ref mut map) = self.free_map {
+            for (i, &value) in values.iter().enumerate() {
+                map.set_free(first + i as u32, value & FAT_MASK == FAT_FREE);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Link `clusters` into a chain in the order given and end it after the
+    /// last one, writing the entries of consecutive clusters together
+    pub fn set_fat_chain(&mut self, clusters: &[u32]) -> Result<()> {
+        let mut start = 0;
+        while start < clusters.len() {
+            let mut end = start + 1;
+            while end < clusters.len() && clusters[end] == clusters[end - 1] + 1 {
+                end += 1;
+            }
+
+            let values: Vec<u32> = (start..end)
+                .map(|i| clusters.get(i + 1).cloned().unwrap_or(FAT_EOC))
+                .collect();
+            self.set_fat_entries(clusters[start], &values)?;
+
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// The cluster following `cluster` in its chain, or None at the end of the chain
+    pub fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>> {
+        let next = self.fat_entry(cluster)?;
+        if next >= FAT_EOC_MIN {
+            Ok(None)
+        } else if self.is_valid_cluster(next) {
+            Ok(Some(next))
+        } else {
+            // A free, bad or reserved cluster inside a chain means the FAT is corrupt
+            Err(Error::new(EIO))
+        }
+    }
+
+    /// Every cluster in the chain starting at `start`
+    pub fn chain(&mut self, start: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        if start == 0 {
+            return Ok(chain);
+        }
+
+        let mut cluster = Some(start);
+        while let Some(current) = cluster {
+            // A chain longer than the volume can only be a loop
+            if chain.len() > self.bpb.cluster_count() as usize {
+                return Err(Error::new(EIO));
+            }
+            chain.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(chain)
+    }
+}
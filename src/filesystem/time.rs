@@ -0,0 +1,146 @@
+//! Conversion between Unix time and the packed dates and times of FAT entries
+
+use std::cmp::{min, max};
+
+/// 1980-01-01 00:00:00, the earliest time FAT can record
+const FAT_MIN: i64 = 315_532_800;
+/// 2107-12-31 23:59:59, the latest time FAT can record
+const FAT_MAX: i64 = 4_354_819_199;
+
+const SECS_PER_DAY: i64 = 86400;
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The (year, month, day) of a count of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// A FAT timestamp: packed date, packed time in 2 second steps and the
+/// hundredths of a second, 0 to 199, that only creation times record
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FatTime {
+    pub date: u16,
+    pub time: u16,
+    pub hundredths: u8,
+}
+
+impl FatTime {
+    /// Encode a time in seconds and nanoseconds since the epoch, clamped to
+    /// the years 1980 to 2107 that FAT can record
+    pub fn from_unix(secs: i64, nsec: u32) -> FatTime {
+        let (secs, nsec) = if secs < FAT_MIN {
+            (FAT_MIN, 0)
+        } else if secs > FAT_MAX {
+            (FAT_MAX, 999_999_999)
+        } else {
+            (secs, min(nsec, 999_999_999))
+        };
+
+        let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+        let secs_of_day = secs % SECS_PER_DAY;
+        FatTime {
+            date: ((year - 1980) << 9 | month << 5 | day) as u16,
+            time: ((secs_of_day / 3600) << 11 | (secs_of_day / 60 % 60) << 5 | (secs_of_day % 60) / 2) as u16,
+            hundredths: ((secs_of_day % 2) * 100 + nsec as i64 / 10_000_000) as u8,
+        }
+    }
+
+    /// Seconds and nanoseconds since the epoch, None if the date was never set
+    pub fn to_unix(&self) -> Option<(i64, u32)> {
+        if self.date == 0 {
+            return None;
+        }
+
+        // Out of range fields are pulled into range rather than rejected
+        let year = (self.date >> 9) as i64 + 1980;
+        let month = max(1, min(12, (self.date >> 5) & 0xF)) as i64;
+        let day = max(1, self.date & 0x1F) as i64;
+        let hour = min(23, self.time >> 11) as i64;
+        let minute = min(59, (self.time >> 5) & 0x3F) as i64;
+        let second = min(29, self.time & 0x1F) as i64 * 2;
+        let hundredths = min(199, self.hundredths) as i64;
+
+        let secs = days_from_civil(year, month, day) * SECS_PER_DAY
+            + hour * 3600 + minute * 60 + second + hundredths / 100;
+        Some((secs, (hundredths % 100) as u32 * 10_000_000))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2000-01-01 00:00:00
+    const Y2K: i64 = 946_684_800;
+
+    #[test]
+    fn civil_round_trip() {
+        for &days in &[-719_468, -1, 0, 1, 3652, 10_957, 11_016, 50_000, 2_932_896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn from_unix_encoding() {
+        let time = FatTime::from_unix(Y2K, 0);
+        assert_eq!(time, FatTime { date: 20 << 9 | 1 << 5 | 1, time: 0, hundredths: 0 });
+
+        let time = FatTime::from_unix(Y2K + 13 * 3600 + 45 * 60 + 7, 250_000_000);
+        assert_eq!(time.time, 13 << 11 | 45 << 5 | 3);
+        assert_eq!(time.hundredths, 125);
+    }
+
+    #[test]
+    fn round_trip() {
+        for &(secs, nsec) in &[(FAT_MIN, 0), (Y2K, 0), (Y2K + 1, 990_000_000), (1_700_000_000, 120_000_000), (FAT_MAX, 990_000_000)] {
+            assert_eq!(FatTime::from_unix(secs, nsec).to_unix(), Some((secs, nsec)));
+        }
+        // Anything finer than hundredths is dropped
+        assert_eq!(FatTime::from_unix(Y2K, 123_456_789).to_unix(), Some((Y2K, 120_000_000)));
+    }
+
+    #[test]
+    fn clamps() {
+        let min = FatTime { date: 1 << 5 | 1, time: 0, hundredths: 0 };
+        assert_eq!(FatTime::from_unix(0, 500_000_000), min);
+        assert_eq!(FatTime::from_unix(FAT_MIN - 1, 0), min);
+        assert_eq!(FatTime::from_unix(i64::min_value(), 0), min);
+
+        let max = FatTime { date: 127 << 9 | 12 << 5 | 31, time: 23 << 11 | 59 << 5 | 29, hundredths: 199 };
+        assert_eq!(FatTime::from_unix(FAT_MAX + 1, 0), max);
+        assert_eq!(FatTime::from_unix(i64::max_value(), 0), max);
+        assert_eq!(max.to_unix(), Some((FAT_MAX, 990_000_000)));
+
+        assert_eq!(FatTime::from_unix(Y2K, 2_000_000_000).hundredths, 99);
+    }
+
+    #[test]
+    fn to_unix_unset_and_out_of_range() {
+        assert_eq!(FatTime::default().to_unix(), None);
+
+        // Month 0, day 0 and an hour of 31 are pulled into range
+        let time = FatTime { date: 20 << 9, time: 31 << 11, hundredths: 255 };
+        assert_eq!(time.to_unix(), Some((Y2K + 23 * 3600 + 1, 990_000_000)));
+    }
+}
@@ -7,7 +7,6 @@ extern crate libc;
 #[cfg(target_os = "redox")]
 extern crate syscall;
 
-extern crate fat;
 extern crate mbr;
 extern crate gpt;
 extern crate fscommon;
@@ -22,9 +21,12 @@ use std::process;
 pub const SECTORSIZE: u32 = 512;
 pub mod partition;
 pub mod mount; 
+pub mod filesystem;
+pub mod options;
 use self::partition::{get_partitions, DiskPartition};
-//use redoxfs::{DiskCache, DiskFile, mount};
-//use uuid::Uuid;
+use self::filesystem::FileSystem;
+use self::mount::mount;
+use self::options::MountOptions;
 
 #[cfg(unix)]
 fn fork() -> isize {
@@ -47,7 +49,7 @@ fn pipe(pipes: &mut [usize; 2]) -> isize {
 }
 
 fn usage() {
-    println!("redox-fatd [mount-prefix]");
+    println!("redox-fatd [-o option[=value],...] [mount-prefix]");
 }
 
 #[cfg(not(target_os = "redox"))]
@@ -92,80 +94,73 @@ fn disk_paths(paths: &mut Vec<String>) {
     }
 }
 
-fn daemon(mountpoint: &str, mut write: File) -> ! {
+fn daemon(mountpoint: &str, options: MountOptions, mut write: File) -> ! {
     let mut paths = vec![];
-    let _s = String::from(mountpoint);
-//  let mut uuid_opt = None;
 
     disk_paths(&mut paths);
-  /*  match *disk_id {
-        DiskId::Path(ref path) => {
-            paths.push(path.clone());
-        },
-        DiskId::Uuid(ref uuid) => {
-            disk_paths(&mut paths);
-            uuid_opt = Some(uuid.clone());
-        },
-    }
-*/
 
-/*
     for path in paths {
-        println!("redox-fatd: opening {}", path);
-        match DiskFile::open(&path).map(|image| DiskCache::new(image)) {
-            Ok(disk) => match redoxfs::FileSystem::open(disk) {
-                Ok(filesystem) => {
-                    println!("redoxfs: opened filesystem on {} with uuid {}", path,
-                             Uuid::from_bytes(&filesystem.header.1.uuid).unwrap().hyphenated());
-
-                    let matches = if let Some(uuid) = uuid_opt {
-                        if &filesystem.header.1.uuid == uuid.as_bytes() {
-                            println!("redoxfs: filesystem on {} matches uuid {}", path, uuid.hyphenated());
-                            true
-                        } else {
-                            println!("redoxfs: filesystem on {} does not match uuid {}", path, uuid.hyphenated());
-                            false
-                        }
-                    } else {
-                        true
-                    };
-
-                    if matches {
-                        match mount(filesystem, &mountpoint, || {
-                            println!("redoxfs: mounted filesystem on {} to {}", path, mountpoint);
-                            let _ = write.write(&[0]);
-                        }) {
-                            Ok(()) => {
-                                process::exit(0);
-                            },
-                            Err(err) => {
-                                println!("redoxfs: failed to mount {} to {}: {}", path, mountpoint, err);
-                            }
-                        }
+        let fat_partitions = match get_partitions(PathBuf::from(&path), 0xc) {
+            Ok(vec) => vec,
+            Err(err) => {
+                println!("redox-fatd: failed to read partitions on {}: {}", path, err);
+                continue;
+            }
+        };
+
+        for partition in fat_partitions {
+            let disk_file = match OpenOptions::new().write(true).read(true).open(&path) {
+                Ok(file) => file,
+                Err(err) => {
+                    println!("redox-fatd: failed to open {}: {}", path, err);
+                    continue;
+                }
+            };
+            let disk_part = DiskPartition::new(disk_file, partition);
+
+            match FileSystem::open(disk_part, options.clone()) {
+                Ok(filesystem) => match mount(filesystem, &mountpoint, || {
+                    println!("redox-fatd: mounted filesystem on {} to {}", path, mountpoint);
+                    let _ = write.write(&[0]);
+                }) {
+                    Ok(()) => {
+                        process::exit(0);
+                    },
+                    Err(err) => {
+                        println!("redox-fatd: failed to mount {} to {}: {}", path, mountpoint, err);
                     }
                 },
-                Err(err) => println!("redoxfs: failed to open filesystem {}: {}", path, err)
-            },
-            Err(err) => println!("redoxfs: failed to open image {}: {}", path, err)
+                Err(err) => println!("redox-fatd: failed to open filesystem on {}: {}", path, err)
+            }
         }
     }
 
-    match *disk_id {
-        DiskId::Path(ref path) => {
-            println!("redoxfs: not able to mount path {}", path);
-        },
-        DiskId::Uuid(ref uuid) => {
-            println!("redoxfs: not able to mount uuid {}", uuid.hyphenated());
-        },
-    }
-*/
+    println!("redox-fatd: no FAT32 filesystem to mount on {}", mountpoint);
+
     let _ = write.write(&[1]);
     process::exit(1);
 }
 
 fn main() {
     let mut args = env::args().skip(1);
-    let mountprefix = match args.next() {
+    let mut options = MountOptions::default();
+    let mut mountprefix = None;
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            let applied = args.next()
+                .ok_or_else(|| "-o requires an argument".to_string())
+                .and_then(|arg| options.apply(&arg));
+            if let Err(err) = applied {
+                println!("redox-fatd: {}", err);
+                usage();
+                process::exit(1);
+            }
+        } else {
+            mountprefix = Some(arg);
+        }
+    }
+
+    let mountprefix = match mountprefix {
         Some(arg) => arg,
         None => {
             println!("redox-fatd: no mount-prefix provided");
@@ -173,25 +168,6 @@ fn main() {
             process::exit(1);
         }
     };
-    let mut paths = vec![];
-    disk_paths(&mut paths);
-
-    for path in paths.iter() {
-        let fat_partitions = match get_partitions(PathBuf::from(path), 0xc) {
-            Ok(vec) => vec,
-            Err(e) => {
-                println!("Error detected {}", e);
-                process::exit(-1);
-            }
-        };
-        for partition in fat_partitions {
-            let disk_file = OpenOptions::new().write(true).read(true).open(path).expect("Path Open Error");
-            let disk_part = DiskPartition::new(disk_file, partition);
-            // TODO mount disk_part
-            println!("Disk Partition: {:?}", disk_part);
-        }
-    }
-
 
     let mut pipes = [0; 2];
     if pipe(&mut pipes) == 0 {
@@ -202,7 +178,7 @@ fn main() {
         if pid == 0 {
             drop(read);
 
-            daemon(&mountprefix, write);
+            daemon(&mountprefix, options, write);
         } else if pid > 0 {
             drop(write);
 
@@ -1 +1,8 @@
+pub use self::redox::mount;
+
 mod redox;
+
+/// `fcntl` command returning the attribute byte of the file's directory entry
+pub const F_GETATTR: usize = 1024;
+/// `fcntl` command replacing the READ_ONLY, HIDDEN, SYSTEM and ARCHIVE attributes with `arg`
+pub const F_SETATTR: usize = 1025;
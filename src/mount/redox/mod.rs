@@ -1,19 +1,35 @@
 extern crate spin;
 
-use syscall;
+use syscall::{self, Packet};
+use syscall::scheme::Scheme;
 use std::fs::File;
-use std::io::{self, Read, Write, Seek};
+use std::io::{self, Read, Write};
 use std::path::Path;
 
+use filesystem::{Disk, FileSystem};
+
+use self::scheme::FileScheme;
+
 mod resource;
 mod scheme;
 
-pub fn mount<D: Read + Write + Seek, P: AsRef<Path>, F: FnMut()>(_filesystem: D, mountpoint: &P, mut callback: F) -> io::Result<()> {
+pub fn mount<D: Disk, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, mut callback: F) -> io::Result<()> {
     let mountpoint = mountpoint.as_ref();
-    let mut _socket = File::create(format!(":{}", mountpoint.display()))?;
+    let mut socket = File::create(format!(":{}", mountpoint.display()))?;
 
     callback();
+
     syscall::setrens(0, 0).expect("redox-fatd: failed to enter null namespace");
+
+    let scheme = FileScheme::new(format!("{}", mountpoint.display()), filesystem);
+    loop {
+        let mut packet = Packet::default();
+        if socket.read(&mut packet)? == 0 {
+            break;
+        }
+        scheme.handle(&mut packet);
+        socket.write(&packet)?;
+    }
+
     Ok(())
-    //TODO Scheme stuff
 }
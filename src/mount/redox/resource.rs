@@ -1,77 +1,242 @@
 use std::cmp::{min, max};
+use std::collections::BTreeMap;
 //use std::time::{SystemTime, UNIX_EPOCH};
-use std::io::{Read, Write, Seek};
-//use fatfs::{FileSystem, File, Dir};
-use fat::{FatFileSystem, File, Directory};
-use std::cell::RefCell;
+use std::slice;
 
 use syscall::data::TimeSpec;
 use syscall::error::{Result};
-use syscall::{Error, EBADF, EBUSY, EINVAL, EISDIR, EPERM};
-use syscall::flag::{O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR, F_GETFL, F_SETFL, MODE_PERM};
+use syscall::{Error, EACCES, EBADF, EINVAL, EISDIR, ENOMEM, EPERM};
+use syscall::flag::{O_ACCMODE, O_APPEND, O_RDONLY, O_WRONLY, O_RDWR, F_GETFL, F_SETFL};
 use syscall::{Stat, SEEK_SET, SEEK_CUR, SEEK_END};
-//use syscall::{Stat};
 
-use partition::DiskPartition;
+use filesystem::{Disk, Extents, FileSystem};
+use mount::{F_GETATTR, F_SETATTR};
 
-pub trait Resource<D: Read + Write + Seek> {
+pub trait Resource<D: Disk> {
     fn block(&self) -> u64;
-    //fn dup(&self) -> Result<Box<Resource<D>>>;
-    fn read(&mut self, buf: &mut [u8], _fs: &mut FatFileSystem<D>) -> Result<usize>;
-    fn write(&mut self, buf: &[u8], fs: &mut FatFileSystem<D>) -> Result<usize>;
-    fn seek(&mut self, offset: usize, whence: usize, fs: &mut FatFileSystem<D>) -> Result<usize>;
-    //TODO: Implement fmap
-    //fn fmap(&mut self, offset: usize, size: usize, maps: &mut Fmaps, fs: &mut DiskPartition) -> Result<usize>;
-    //fn funmap(&mut self, maps: &mut Fmaps, fs: &mut DiskPartition) -> Result<usize>;
-    fn fchmod(&mut self, mode: u16, fs: &mut FatFileSystem<D>) -> Result<usize>;
-    fn fchown(&mut self, uid: u32, gid: u32, fs: &mut FatFileSystem<D>) -> Result<usize>;
-    fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize>;
+    fn dup(&self, buf: &[u8]) -> Result<Box<Resource<D>>>;
+    fn read(&mut self, buf: &mut [u8], _fs: &mut FileSystem<D>) -> Result<usize>;
+    fn write(&mut self, buf: &[u8], fs: &mut FileSystem<D>) -> Result<usize>;
+    fn seek(&mut self, offset: usize, whence: usize, fs: &mut FileSystem<D>) -> Result<usize>;
+    fn fmap(&mut self, offset: usize, size: usize, maps: &mut Fmaps, fs: &mut FileSystem<D>) -> Result<usize>;
+    fn funmap(&mut self, maps: &mut Fmaps, fs: &mut FileSystem<D>) -> Result<usize>;
+    fn fchmod(&mut self, mode: u16, fs: &mut FileSystem<D>) -> Result<usize>;
+    fn fchown(&mut self, uid: u32, gid: u32, fs: &mut FileSystem<D>) -> Result<usize>;
+    fn fcntl(&mut self, cmd: usize, arg: usize, fs: &mut FileSystem<D>) -> Result<usize>;
     fn path(&self, buf: &mut [u8]) -> Result<usize>;
-    fn stat(&self, _stat: &mut Stat, fs: &mut FatFileSystem<D>) -> Result<usize>;
-    //fn sync(&mut self, maps: &mut Fmaps, fs: &mut DiskPartition) -> Result<usize>;
-    fn truncate(&mut self, len: usize, fs: &mut FatFileSystem<D>) -> Result<usize>;
-    fn utimens(&mut self, times: &[TimeSpec], fs: &mut FatFileSystem<D>) -> Result<usize>;
+    fn set_path(&mut self, path: &str);
+    fn stat(&self, _stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize>;
+    fn sync(&mut self, maps: &mut Fmaps, fs: &mut FileSystem<D>) -> Result<usize>;
+    fn truncate(&mut self, len: usize, fs: &mut FileSystem<D>) -> Result<usize>;
+    fn utimens(&mut self, times: &[TimeSpec], fs: &mut FileSystem<D>) -> Result<usize>;
 }
 
-pub struct DirResource<'a, T: Read + Write + Seek + 'a> {
+const PAGE_SIZE: usize = 4096;
+
+extern "C" {
+    fn memalign(align: usize, size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+}
+
+/// A page aligned copy of part of a file, handed out by `fmap`
+pub struct Fmap {
+    block: u64,
+    offset: u64,
+    writable: bool,
+    data: &'static mut [u8],
+    /// The contents as last read from or written to disk, to find dirty pages
+    clean: Vec<u8>,
+    extents: Extents,
+}
+
+impl Fmap {
+    fn new<D: Disk>(block: u64, offset: u64, size: usize, writable: bool, fs: &mut FileSystem<D>) -> Result<Fmap> {
+        // Memory handed to fmap must be page aligned and sized
+        let aligned_size = (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let address = unsafe { memalign(PAGE_SIZE, aligned_size) };
+        if address.is_null() {
+            return Err(Error::new(ENOMEM));
+        }
+
+        let mut fmap = Fmap {
+            block: block,
+            offset: offset,
+            writable: writable,
+            data: unsafe { slice::from_raw_parts_mut(address, aligned_size) },
+            clean: Vec::new(),
+            extents: Extents::new(),
+        };
+
+        // Whatever lies past the end of the file reads as zeroes
+        let count = fs.read_node(block, offset, fmap.data, &mut fmap.extents)?;
+        for b in fmap.data[count..].iter_mut() {
+            *b = 0;
+        }
+        fmap.clean = fmap.data.to_vec();
+
+        Ok(fmap)
+    }
+
+    pub fn address(&self) -> usize {
+        self.data.as_ptr() as usize
+    }
+
+    /// Write the pages changed since the last sync back to the file. Changes
+    /// past the end of the file are dropped, as mapping does not grow a file
+    pub fn sync<D: Disk>(&mut self, fs: &mut FileSystem<D>) -> Result<()> {
+        if ! self.writable {
+            return Ok(());
+        }
+
+        let size = fs.node_len(self.block)?;
+        if size <= self.offset {
+            return Ok(());
+        }
+        let end = min((size - self.offset) as usize, self.data.len());
+
+        let mut page = 0;
+        while page < end {
+            let page_end = min(page + PAGE_SIZE, end);
+            if self.data[page..page_end] != self.clean[page..page_end] {
+                fs.write_node(self.block, self.offset + page as u64, &self.data[page..page_end], &mut self.extents)?;
+                self.clean[page..page_end].copy_from_slice(&self.data[page..page_end]);
+            }
+            page = page_end;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Fmap {
+    fn drop(&mut self) {
+        unsafe { free(self.data.as_mut_ptr()); }
+    }
+}
+
+/// Every mapping handed out, by address
+pub type Fmaps = BTreeMap<usize, Fmap>;
+
+/// Fill in `stat` from the directory entry of the open inode `ino`
+fn stat_node<D: Disk>(ino: u64, stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize> {
+    let entry = fs.inode_entry(ino)?;
+
+    let (mtime, mtime_nsec) = fs.unix_time(entry.modified()).unwrap_or((0, 0));
+    let (atime, atime_nsec) = fs.unix_time(entry.accessed()).unwrap_or((mtime, mtime_nsec));
+    // FAT has no change time, the creation time is the closest it keeps
+    let (ctime, ctime_nsec) = fs.unix_time(entry.created()).unwrap_or((mtime, mtime_nsec));
+
+    *stat = Stat {
+        st_dev: 0, // TODO
+        st_ino: ino,
+        st_mode: fs.mode(&entry),
+        st_uid: fs.options.uid,
+        st_gid: fs.options.gid,
+        st_nlink: 1,
+        st_size: entry.size as u64,
+        st_blksize: fs.bpb.cluster_size() as u32,
+        st_mtime: mtime as u64,
+        st_mtime_nsec: mtime_nsec,
+        st_atime: atime as u64,
+        st_atime_nsec: atime_nsec,
+        st_ctime: ctime as u64,
+        st_ctime_nsec: ctime_nsec,
+        ..Default::default()
+    };
+
+    Ok(0)
+}
+
+/// Only the owner given at mount time and root may change the mode, of
+/// which FAT keeps whether any write bit of a file is set. Directories keep
+/// nothing, their READ_ONLY attribute meaning something else to Windows.
+pub fn set_mode<D: Disk>(block: u64, uid: u32, mode: u16, fs: &mut FileSystem<D>) -> Result<usize> {
+    if uid != 0 && uid != fs.options.uid {
+        return Err(Error::new(EPERM));
+    }
+    if ! fs.inode_entry(block)?.is_dir() {
+        fs.set_read_only(block, mode & 0o222 == 0)?;
+    }
+    Ok(0)
+}
+
+/// Set the access and modification times from `times`, which only the owner
+/// given at mount time and root may do
+fn set_times<D: Disk>(block: u64, uid: u32, times: &[TimeSpec], fs: &mut FileSystem<D>) -> Result<usize> {
+    if uid != 0 && uid != fs.options.uid {
+        return Err(Error::new(EPERM));
+    }
+
+    let atime = times.get(0).map(|time| fs.fat_time(time.tv_sec, time.tv_nsec as u32));
+    let mtime = times.get(1).map(|time| fs.fat_time(time.tv_sec, time.tv_nsec as u32));
+    fs.set_times(block, atime, mtime)?;
+    Ok(0)
+}
+
+/// FAT has no owners, so a chown only succeeds when it asks for the owner
+/// given at mount time, a `u32::MAX` id leaving that id alone
+fn check_owner<D: Disk>(uid: u32, gid: u32, fs: &mut FileSystem<D>) -> Result<usize> {
+    if (uid == u32::max_value() || uid == fs.options.uid)
+        && (gid == u32::max_value() || gid == fs.options.gid) {
+        Ok(0)
+    } else {
+        Err(Error::new(EPERM))
+    }
+}
+
+/// Replace the DOS attributes of the open inode `block` with `arg`, which
+/// like the mode only the owner given at mount time and root may do
+fn set_attributes<D: Disk>(block: u64, uid: u32, arg: usize, fs: &mut FileSystem<D>) -> Result<usize> {
+    if uid != 0 && uid != fs.options.uid {
+        return Err(Error::new(EPERM));
+    }
+    if arg > u8::max_value() as usize {
+        return Err(Error::new(EINVAL));
+    }
+    fs.set_attributes(block, arg as u8)?;
+    Ok(0)
+}
+
+pub struct DirResource {
     path: String,
     block: u64,
     data: Option<Vec<u8>>,
     seek: usize,
     uid: u32,
-    //dir: RefCell<Dir<'a, T>>
 }
 
-impl<'a, T: Read + Write + Seek + 'a> DirResource<'a, T> {
-    pub fn new(path: String, block: u64, data: Option<Vec<u8>>, uid: u32) -> DirResource<'a, T> {
+impl DirResource {
+    pub fn new(path: String, block: u64, data: Option<Vec<u8>>, uid: u32) -> DirResource {
         DirResource {
             path: path,
             block: block,
             data: data,
             seek: 0,
             uid: uid,
-            //dir: RefCell::new(dir)
         }
     }
 }
 
-impl<'a, D: Read + Write + Seek + 'a> Resource<D> for DirResource<'a, D> {
+impl<D: Disk> Resource<D> for DirResource {
     fn block(&self) -> u64 {
         self.block
     }
-/*
-    fn dup(&self) -> Result<Box<Resource<D>>> {
+
+    fn dup(&self, buf: &[u8]) -> Result<Box<Resource<D>>> {
+        if ! buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
         Ok(Box::new(DirResource {
             path: self.path.clone(),
             block: self.block,
             data: self.data.clone(),
             seek: self.seek,
-            uid: self.uid,
-            dir: self.dir.clone()
+            uid: self.uid
         }))
     }
-*/
-    fn read(&mut self, buf: &mut [u8], _fs: &mut FatFileSystem<D>) -> Result<usize> {
+
+    fn read(&mut self, buf: &mut [u8], _fs: &mut FileSystem<D>) -> Result<usize> {
         let data = self.data.as_ref().ok_or(Error::new(EISDIR))?;
         let mut i = 0;
         while i < buf.len() && self.seek < data.len() {
@@ -82,11 +247,11 @@ impl<'a, D: Read + Write + Seek + 'a> Resource<D> for DirResource<'a, D> {
         Ok(i)
     }
 
-    fn write(&mut self, _buf: &[u8], _fs: &mut FatFileSystem<D>) -> Result<usize> {
+    fn write(&mut self, _buf: &[u8], _fs: &mut FileSystem<D>) -> Result<usize> {
         Err(Error::new(EBADF))
     }
 
-    fn seek(&mut self, offset: usize, whence: usize, _fs: &mut FatFileSystem<D>) -> Result<usize> {
+    fn seek(&mut self, offset: usize, whence: usize, _fs: &mut FileSystem<D>) -> Result<usize> {
         let data = self.data.as_ref().ok_or(Error::new(EBADF))?;
         self.seek = match whence {
             SEEK_SET => max(0, min(data.len() as isize, offset as isize)) as usize,
@@ -98,16 +263,28 @@ impl<'a, D: Read + Write + Seek + 'a> Resource<D> for DirResource<'a, D> {
         Ok(self.seek)
     }
 
-    fn fchmod(&mut self, mode: u16, fs: &mut FatFileSystem<D>) -> Result<usize> {
-        Ok(0) // FAT32 does not support file permissions
+    fn fmap(&mut self, _offset: usize, _size: usize, _maps: &mut Fmaps, _fs: &mut FileSystem<D>) -> Result<usize> {
+        Err(Error::new(EBADF))
     }
 
-    fn fchown(&mut self, uid: u32, gid: u32, fs: &mut FatFileSystem<D>) -> Result<usize> {
-        Ok(0) // FAT32 does not support file permissions
+    fn funmap(&mut self, _maps: &mut Fmaps, _fs: &mut FileSystem<D>) -> Result<usize> {
+        Ok(0)
     }
 
-    fn fcntl(&mut self, _cmd: usize, _arg: usize) -> Result<usize> {
-        Err(Error::new(EBADF))
+    fn fchmod(&mut self, mode: u16, fs: &mut FileSystem<D>) -> Result<usize> {
+        set_mode(self.block, self.uid, mode, fs)
+    }
+
+    fn fchown(&mut self, uid: u32, gid: u32, fs: &mut FileSystem<D>) -> Result<usize> {
+        check_owner(uid, gid, fs)
+    }
+
+    fn fcntl(&mut self, cmd: usize, arg: usize, fs: &mut FileSystem<D>) -> Result<usize> {
+        match cmd {
+            F_GETATTR => fs.attributes(self.block).map(|attr| attr as usize),
+            F_SETATTR => set_attributes(self.block, self.uid, arg, fs),
+            _ => Err(Error::new(EBADF))
+        }
     }
 
     fn path(&self, buf: &mut [u8]) -> Result<usize> {
@@ -122,100 +299,113 @@ impl<'a, D: Read + Write + Seek + 'a> Resource<D> for DirResource<'a, D> {
         Ok(i)
     }
 
-    //TODO: Implement this using the FAT32 node metadata
-    fn stat(&self, stat: &mut Stat, fs: &mut FatFileSystem<D>) -> Result<usize> {
-       /* let node = fs.node(self.block)?;
-
-        *stat = Stat {
-            st_dev: 0, // TODO
-            st_ino: node.0,
-            st_mode: node.1.mode,
-            st_nlink: 1,
-            st_uid: node.1.uid,
-            st_gid: node.1.gid,
-            st_size: fs.node_len(self.block)?,
-            st_mtime: node.1.mtime,
-            st_mtime_nsec: node.1.mtime_nsec,
-            st_ctime: node.1.ctime,
-            st_ctime_nsec: node.1.ctime_nsec,
-            ..Default::default()
-        };
-        */
+    fn set_path(&mut self, path: &str) {
+        self.path = path.to_string();
+    }
+
+    fn stat(&self, stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize> {
+        stat_node(self.block, stat, fs)
+    }
+
+    fn sync(&mut self, _maps: &mut Fmaps, fs: &mut FileSystem<D>) -> Result<usize> {
+        fs.sync()?;
         Ok(0)
     }
 
-    fn truncate(&mut self, _len: usize, _fs: &mut FatFileSystem<D>) -> Result<usize> {
+    fn truncate(&mut self, _len: usize, _fs: &mut FileSystem<D>) -> Result<usize> {
         Err(Error::new(EBADF))
     }
 
-    fn utimens(&mut self, _times: &[TimeSpec], _fs: &mut FatFileSystem<D>) -> Result<usize> {
-        Err(Error::new(EBADF))
+    fn utimens(&mut self, times: &[TimeSpec], fs: &mut FileSystem<D>) -> Result<usize> {
+        set_times(self.block, self.uid, times, fs)
     }
 }
 
-pub struct FileResource<'a, T: Read + Write + Seek + 'a> {
+pub struct FileResource {
     path: String,
     block: u64,
     flags: usize,
     seek: u64,
     uid: u32,
-    //file: File<'a, T>
+    /// Addresses of the mappings made through this handle
+    maps: Vec<usize>,
+    /// The clusters of the file read so far
+    extents: Extents,
 }
 
-impl<'a, T: Read + Write + Seek + 'a> FileResource<'a, T> {
-    pub fn new(fpath: String, block: u64, flags: usize, seek: u64, uid: u32) -> FileResource<T> {
+impl FileResource {
+    pub fn new(fpath: String, block: u64, flags: usize, seek: u64, uid: u32) -> FileResource {
         FileResource {
             path: fpath,
             block: block,
             flags: flags,
             seek: seek,
             uid: uid,
-            //file: file
+            maps: Vec::new(),
+            extents: Extents::new(),
         }
     }
 }
 
-impl<'a, D: Read + Write + Seek + 'a> Resource<D> for FileResource<'a, D> {
+impl<D: Disk> Resource<D> for FileResource {
     fn block(&self) -> u64 {
         self.block
     }
-    /*
-    fn dup(&self) -> Result<Box<Resource<D>>> {
+
+    /// An empty `buf` copies the handle, seek position included. Otherwise
+    /// `buf` is `r`, `w` or `rw` and the file is reopened with that access,
+    /// which must not exceed the access of this handle
+    fn dup(&self, buf: &[u8]) -> Result<Box<Resource<D>>> {
+        let (flags, seek) = if buf.is_empty() {
+            (self.flags, self.seek)
+        } else {
+            let accmode = match buf {
+                b"r" => O_RDONLY,
+                b"w" => O_WRONLY,
+                b"rw" => O_RDWR,
+                _ => return Err(Error::new(EINVAL))
+            };
+            if accmode & ! (self.flags & O_ACCMODE) != 0 {
+                return Err(Error::new(EACCES));
+            }
+            ((self.flags & ! O_ACCMODE) | accmode, 0)
+        };
+
         Ok(Box::new(FileResource {
             path: self.path.clone(),
             block: self.block,
-            flags: self.flags,
-            seek: self.seek,
+            flags: flags,
+            seek: seek,
             uid: self.uid,
-            fmap: None
+            maps: Vec::new(),
+            extents: self.extents.clone(),
         }))
     }
-    */
-    fn read(&mut self, buf: &mut [u8], fs: &mut FatFileSystem<D>) -> Result<usize> {
-        /*if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_RDONLY {
-            let count = fs.read_node(self.block, self.seek, buf)?;
+    fn read(&mut self, buf: &mut [u8], fs: &mut FileSystem<D>) -> Result<usize> {
+        if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_RDONLY {
+            let count = fs.read_node(self.block, self.seek, buf, &mut self.extents)?;
             self.seek += count as u64;
             Ok(count)
         } else {
             Err(Error::new(EBADF))
-        }*/
-        Ok(0) // TODO
+        }
     }
 
-    fn write(&mut self, buf: &[u8], fs: &mut FatFileSystem<D>) -> Result<usize> {
-        /*if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
-            let mtime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-            let count = fs.write_node(self.block, self.seek, buf, mtime.as_secs(), mtime.subsec_nanos())?;
+    fn write(&mut self, buf: &[u8], fs: &mut FileSystem<D>) -> Result<usize> {
+        if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
+            if self.flags & O_APPEND == O_APPEND {
+                self.seek = fs.node_len(self.block)?;
+            }
+            let count = fs.write_node(self.block, self.seek, buf, &mut self.extents)?;
             self.seek += count as u64;
             Ok(count)
         } else {
             Err(Error::new(EBADF))
-        }*/
-        Ok(0) // TODO
+        }
     }
     
-    fn seek(&mut self, offset: usize, whence: usize, fs: &mut FatFileSystem<D>) -> Result<usize> {
-        /*let size = fs.node_len(self.block)?;
+    fn seek(&mut self, offset: usize, whence: usize, fs: &mut FileSystem<D>) -> Result<usize> {
+        let size = fs.node_len(self.block)?;
 
         self.seek = match whence {
             SEEK_SET => max(0, offset as i64) as u64,
@@ -225,20 +415,49 @@ impl<'a, D: Read + Write + Seek + 'a> Resource<D> for FileResource<'a, D> {
         };
 
         Ok(self.seek as usize)
-        */
-        Ok(0) // TODO
     }
 
-    fn fchmod(&mut self, mode: u16, fs: &mut FatFileSystem<D>) -> Result<usize> {
-        Ok(0) // FAT does not have file permissions
+    fn fmap(&mut self, offset: usize, size: usize, maps: &mut Fmaps, fs: &mut FileSystem<D>) -> Result<usize> {
+        if size == 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_RDONLY {
+            let writable = self.flags & O_ACCMODE == O_RDWR;
+            let fmap = Fmap::new(self.block, offset as u64, size, writable, fs)?;
+            let address = fmap.address();
+            maps.insert(address, fmap);
+            self.maps.push(address);
+            Ok(address)
+        } else {
+            Err(Error::new(EACCES))
+        }
+    }
+
+    fn funmap(&mut self, maps: &mut Fmaps, fs: &mut FileSystem<D>) -> Result<usize> {
+        let mut result = Ok(0);
+        for address in self.maps.drain(..) {
+            if let Some(mut fmap) = maps.remove(&address) {
+                if let Err(err) = fmap.sync(fs) {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+
+    fn fchmod(&mut self, mode: u16, fs: &mut FileSystem<D>) -> Result<usize> {
+        set_mode(self.block, self.uid, mode, fs)
     }
 
-    fn fchown(&mut self, uid: u32, gid: u32, fs: &mut FatFileSystem<D>) -> Result<usize> {
-        Ok(0) // FAT does not have file permissions
+    fn fchown(&mut self, uid: u32, gid: u32, fs: &mut FileSystem<D>) -> Result<usize> {
+        check_owner(uid, gid, fs)
     }
 
-    fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize> {
+    fn fcntl(&mut self, cmd: usize, arg: usize, fs: &mut FileSystem<D>) -> Result<usize> {
         match cmd {
+            F_GETATTR => fs.attributes(self.block).map(|attr| attr as usize),
+            F_SETATTR => set_attributes(self.block, self.uid, arg, fs),
             F_GETFL => Ok(self.flags),
             F_SETFL => {
                 self.flags = (self.flags & O_ACCMODE) | (arg & ! O_ACCMODE);
@@ -260,56 +479,34 @@ impl<'a, D: Read + Write + Seek + 'a> Resource<D> for FileResource<'a, D> {
         Ok(i)
     }
 
-    fn stat(&self, stat: &mut Stat, fs: &mut FatFileSystem<D>) -> Result<usize> {
-        /*let node = fs.node(self.block)?;
-
-        *stat = Stat {
-            st_dev: 0, // TODO
-            st_ino: node.0,
-            st_mode: node.1.mode,
-            st_nlink: 1,
-            st_uid: node.1.uid,
-            st_gid: node.1.gid,
-            st_size: fs.node_len(self.block)?,
-            st_mtime: node.1.mtime,
-            st_mtime_nsec: node.1.mtime_nsec,
-            st_ctime: node.1.ctime,
-            st_ctime_nsec: node.1.ctime_nsec,
-            ..Default::default()
-        };
-        */
-        Ok(0)
+    fn set_path(&mut self, path: &str) {
+        self.path = path.to_string();
+    }
+
+    fn stat(&self, stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize> {
+        stat_node(self.block, stat, fs)
     }
     
-    fn truncate(&mut self, len: usize, fs: &mut FatFileSystem<D>) -> Result<usize> {
-        /*if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
-            fs.node_set_len(self.block, len as u64)?;
+    fn sync(&mut self, maps: &mut Fmaps, fs: &mut FileSystem<D>) -> Result<usize> {
+        for address in self.maps.iter() {
+            if let Some(fmap) = maps.get_mut(address) {
+                fmap.sync(fs)?;
+            }
+        }
+        fs.sync()?;
+        Ok(0)
+    }
+
+    fn truncate(&mut self, len: usize, fs: &mut FileSystem<D>) -> Result<usize> {
+        if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
+            fs.node_set_len(self.block, len as u64, &mut self.extents)?;
             Ok(0)
         } else {
             Err(Error::new(EBADF))
-        }*/
-        Ok(0) // TODO
-
+        }
     }
 
-    fn utimens(&mut self, times: &[TimeSpec], fs: &mut FatFileSystem<D>) -> Result<usize> {
-        /*let mut node = fs.node(self.block)?;
-
-        if node.1.uid == self.uid || self.uid == 0 {
-            if let Some(mtime) = times.get(1) {
-
-                node.1.mtime = mtime.tv_sec as u64;
-                node.1.mtime_nsec = mtime.tv_nsec as u32;
-
-                fs.write_at(node.0, &node.1)?;
-
-                Ok(0)
-            } else {
-                Ok(0)
-            }
-        } else {
-            Err(Error::new(EPERM))
-        }*/
-        Ok(0) // TODO
+    fn utimens(&mut self, times: &[TimeSpec], fs: &mut FileSystem<D>) -> Result<usize> {
+        set_times(self.block, self.uid, times, fs)
     }
 }
@@ -1,48 +1,508 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
-use std::result::Result as StdResult;
 use std::str;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::io::{Read, Write, Seek};
 
 use syscall::data::{Stat, StatVfs, TimeSpec};
-use syscall::error::{Error, Result, EACCES, EEXIST, EISDIR, ENOTDIR, ENOTEMPTY, EPERM, ENOENT, EBADF, ELOOP, EINVAL};
-use syscall::flag::{O_APPEND, O_CREAT, O_DIRECTORY, O_STAT, O_EXCL, O_TRUNC, O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR, MODE_PERM, O_SYMLINK, O_NOFOLLOW};
+use syscall::error::{Error, Result, EACCES, EBADF, EEXIST, EISDIR, ENOENT, ENOTDIR};
+use syscall::flag::{O_CREAT, O_DIRECTORY, O_STAT, O_EXCL, O_TRUNC, O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR};
 use syscall::scheme::Scheme;
 
-use SECTORSIZE;
-//use fatfs::FileSystem;
-use fat::FatFileSystem;
+use filesystem::{DirEntry, Disk, Extents, FileSystem, Node, ATTR_ARCHIVE, PERM_EXEC, PERM_READ, PERM_WRITE};
 
-use super::resource::{Resource, DirResource, FileResource};
+use super::resource::{Resource, DirResource, FileResource, Fmaps, set_mode};
 use super::spin::Mutex;
 
-pub struct FileScheme<D: Read + Write + Seek> {
+pub struct FileScheme<D: Disk> {
     name: String,
-    fs: RefCell<FatFileSystem<D>>,
+    fs: RefCell<FileSystem<D>>,
     next_id: AtomicUsize,
     files: Mutex<BTreeMap<usize, Box<Resource<D>>>>,
-    // fmaps: Mutex<Fmaps> TODO
+    fmaps: Mutex<Fmaps>,
 }
 
-impl<D: Read + Write + Seek> FileScheme<D> {
-    pub fn new(name: String, fs: FatFileSystem<D>) -> FileScheme<D> {
+impl<D: Disk> FileScheme<D> {
+    pub fn new(name: String, fs: FileSystem<D>) -> FileScheme<D> {
         FileScheme {
             name: name,
             fs: RefCell::new(fs),
             next_id: AtomicUsize::new(1),
             files: Mutex::new(BTreeMap::new()),
-            // fmaps: Mutex::new(Fmaps::default()) TODO
+            fmaps: Mutex::new(Fmaps::new()),
         }
     }
 }
 
+/// The path `file` was opened with, relative to the root of the filesystem
+fn resource_path<D: Disk>(file: &Resource<D>) -> Result<String> {
+    let mut buf = [0; 4096];
+    let count = file.path(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf[..count]).into_owned())
+}
+
+/// Split `path` into the path of its parent directory and its last component
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path)
+    }
+}
+
+fn check_permission<D: Disk>(fs: &FileSystem<D>, entry: &DirEntry, uid: u32, gid: u32, op: u16) -> Result<()> {
+    if fs.permission(entry, uid, gid, op) {
+        Ok(())
+    } else {
+        Err(Error::new(EACCES))
+    }
+}
+
+/// Every directory has the same search permission, as only write access
+/// differs between them, so the way to `path` may be searched if the root can
+fn check_search<D: Disk>(fs: &FileSystem<D>, path: &str, uid: u32, gid: u32) -> Result<()> {
+    if path.is_empty() {
+        return Ok(());
+    }
+    check_permission(fs, &DirEntry::root(fs.bpb.root_cluster), uid, gid, PERM_EXEC)
+}
+
+/// Find the parent directory of `path`, which `uid` and `gid` must be allowed to modify
+fn find_parent<'a, D: Disk>(fs: &mut FileSystem<D>, path: &'a str, uid: u32, gid: u32) -> Result<(Node, &'a str)> {
+    let (parent_path, name) = split_path(path);
+
+    let parent = fs.find_node(parent_path)?;
+    if ! parent.is_dir() {
+        return Err(Error::new(ENOTDIR));
+    }
+    check_permission(fs, &parent.entry, uid, gid, PERM_WRITE | PERM_EXEC)?;
+
+    Ok((parent, name))
+}
+
+impl<D: Disk> Scheme for FileScheme<D> {
+    fn open(&self, url: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+
+        // println!("Open '{}' {:X}", path, flags);
+
+        let mut fs = self.fs.borrow_mut();
+        check_search(&fs, path, uid, gid)?;
+
+        let resource: Box<Resource<D>> = match fs.find_node(path) {
+            Ok(node) => {
+                if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL {
+                    return Err(Error::new(EEXIST));
+                }
+
+                if node.is_dir() {
+                    if flags & O_STAT == O_STAT {
+                        Box::new(DirResource::new(path.to_string(), fs.open_inode(&node), None, uid))
+                    } else if flags & O_ACCMODE == O_RDONLY {
+                        check_permission(&fs, &node.entry, uid, gid, PERM_READ)?;
+
+                        let mut data = Vec::new();
+                        for child in fs.read_dir(node.entry.cluster)? {
+                            if ! data.is_empty() {
+                                data.push(b'\n');
+                            }
+                            data.extend_from_slice(child.name.as_bytes());
+                        }
+
+                        Box::new(DirResource::new(path.to_string(), fs.open_inode(&node), Some(data), uid))
+                    } else {
+                        return Err(Error::new(EISDIR));
+                    }
+                } else {
+                    if flags & O_DIRECTORY == O_DIRECTORY && flags & O_STAT != O_STAT {
+                        return Err(Error::new(ENOTDIR));
+                    }
+
+                    if flags & O_STAT != O_STAT {
+                        let mut op = 0;
+                        if flags & O_ACCMODE == O_RDONLY || flags & O_ACCMODE == O_RDWR {
+                            op |= PERM_READ;
+                        }
+                        if flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR {
+                            op |= PERM_WRITE;
+                        }
+                        check_permission(&fs, &node.entry, uid, gid, op)?;
+                    }
+
+                    let ino = fs.open_inode(&node);
+                    if flags & O_TRUNC == O_TRUNC
+                        && (flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR) {
+                        if let Err(err) = fs.node_set_len(ino, 0, &mut Extents::new()) {
+                            fs.close_inode(ino)?;
+                            return Err(err);
+                        }
+                    }
+
+                    Box::new(FileResource::new(path.to_string(), ino, flags, 0, uid))
+                }
+            },
+            Err(err) => if err.errno == ENOENT && flags & O_CREAT == O_CREAT {
+                let (parent, name) = find_parent(&mut fs, path, uid, gid)?;
+
+                if flags & O_DIRECTORY == O_DIRECTORY {
+                    let node = fs.create_dir(&parent, name)?;
+                    Box::new(DirResource::new(path.to_string(), fs.open_inode(&node), Some(Vec::new()), uid))
+                } else {
+                    let entry = fs.new_entry(ATTR_ARCHIVE, 0);
+                    let node = fs.create_node(&parent, name, entry)?;
+                    Box::new(FileResource::new(path.to_string(), fs.open_inode(&node), flags, 0, uid))
+                }
+            } else {
+                return Err(err);
+            }
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.files.lock().insert(id, resource);
+
+        Ok(id)
+    }
+
+    fn chmod(&self, url: &[u8], mode: u16, uid: u32, gid: u32) -> Result<usize> {
+        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
 
-fn example<D: Read + Write + Seek+ 'static>(fs_scheme: &'static mut FileScheme<D>, fs: FatFileSystem<D>) {
-    //let mut fs_scheme = FileScheme::new(String::from("some"), fs);
-    let bor_fs = fs_scheme.fs.borrow_mut();
-    let mut root = bor_fs.root_dir().clone();
-    let b = Box::new(DirResource::new(String::from("hehe"), 0, None, 0, root.clone()));
-    fs_scheme.files.lock().insert(0, b);
+        // println!("Chmod '{}' {:o}", path, mode);
+
+        let mut fs = self.fs.borrow_mut();
+        check_search(&fs, path, uid, gid)?;
+        let node = fs.find_node(path)?;
+
+        let ino = fs.open_inode(&node);
+        let result = set_mode(ino, uid, mode, &mut fs);
+        fs.close_inode(ino)?;
+        result
+    }
+
+    fn rmdir(&self, url: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+
+        // println!("Rmdir '{}'", path);
+
+        let mut fs = self.fs.borrow_mut();
+        check_search(&fs, path, uid, gid)?;
+        let node = fs.find_node(path)?;
+        find_parent(&mut fs, path, uid, gid)?;
+        fs.remove_dir(&node)?;
+
+        Ok(0)
+    }
+
+    fn unlink(&self, url: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+
+        // println!("Unlink '{}'", path);
+
+        let mut fs = self.fs.borrow_mut();
+        check_search(&fs, path, uid, gid)?;
+        let node = fs.find_node(path)?;
+        find_parent(&mut fs, path, uid, gid)?;
+        fs.remove_file(&node)?;
+
+        Ok(0)
+    }
+
+    /* Resource operations */
+
+    fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
+        // println!("Dup {}", old_id);
+        let mut files = self.files.lock();
+        let resource = if let Some(old_resource) = files.get(&old_id) {
+            old_resource.dup(buf)?
+        } else {
+            return Err(Error::new(EBADF));
+        };
+        self.fs.borrow_mut().dup_inode(resource.block());
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        files.insert(id, resource);
+
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        // println!("Read {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
+        let mut files = self.files.lock();
+        if let Some(file) = files.get_mut(&id) {
+            file.read(buf, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        // println!("Write {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
+        let mut files = self.files.lock();
+        if let Some(file) = files.get_mut(&id) {
+            file.write(buf, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn seek(&self, id: usize, pos: usize, whence: usize) -> Result<usize> {
+        // println!("Seek {}, {} {}", id, pos, whence);
+        let mut files = self.files.lock();
+        if let Some(file) = files.get_mut(&id) {
+            file.seek(pos, whence, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fchmod(&self, id: usize, mode: u16) -> Result<usize> {
+        let mut files = self.files.lock();
+        if let Some(file) = files.get_mut(&id) {
+            file.fchmod(mode, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fchown(&self, id: usize, uid: u32, gid: u32) -> Result<usize> {
+        let mut files = self.files.lock();
+        if let Some(file) = files.get_mut(&id) {
+            file.fchown(uid, gid, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let mut files = self.files.lock();
+        if let Some(file) = files.get_mut(&id) {
+            file.fcntl(cmd, arg, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fmap(&self, id: usize, offset: usize, size: usize) -> Result<usize> {
+        // println!("Fmap {}, {} {}", id, offset, size);
+        let mut files = self.files.lock();
+        let mut fmaps = self.fmaps.lock();
+        if let Some(file) = files.get_mut(&id) {
+            file.fmap(offset, size, &mut fmaps, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        // println!("Fpath {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
+        let files = self.files.lock();
+        if let Some(file) = files.get(&id) {
+            let name = self.name.as_bytes();
+
+            let mut i = 0;
+            while i < buf.len() && i < name.len() {
+                buf[i] = name[i];
+                i += 1;
+            }
+            if i < buf.len() {
+                buf[i] = b':';
+                i += 1;
+            }
+            if i < buf.len() {
+                buf[i] = b'/';
+                i += 1;
+            }
+
+            file.path(&mut buf[i..]).map(|count| i + count)
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn frename(&self, id: usize, url: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+
+        // println!("Frename {}, {}", id, path);
+
+        let mut files = self.files.lock();
+        let (ino, old_path) = match files.get(&id) {
+            Some(file) => (file.block(), resource_path(&**file)?),
+            None => return Err(Error::new(EBADF))
+        };
+
+        let mut fs = self.fs.borrow_mut();
+        check_search(&fs, path, uid, gid)?;
+        // The path of the handle may name another file by now if this one
+        // was removed, so go by the inode
+        let node = fs.inode_node(ino)?;
+        find_parent(&mut fs, &old_path, uid, gid)?;
+        let (parent, name) = find_parent(&mut fs, path, uid, gid)?;
+        fs.rename_node(&node, &parent, name)?;
+
+        // Handles keep their inode through the move, but their paths change,
+        // and so do the paths of handles below a directory
+        let prefix = format!("{}/", old_path);
+        for file in files.values_mut() {
+            if file.block() == ino {
+                file.set_path(path);
+            } else if node.is_dir() {
+                let file_path = resource_path(&**file)?;
+                if file_path.starts_with(&prefix) {
+                    file.set_path(&format!("{}/{}", path, &file_path[prefix.len()..]));
+                }
+            }
+        }
+        Ok(0)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        // println!("Fstat {}, {:X}", id, stat as *mut Stat as usize);
+        let files = self.files.lock();
+        if let Some(file) = files.get(&id) {
+            file.stat(stat, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fstatvfs(&self, id: usize, stat: &mut StatVfs) -> Result<usize> {
+        // println!("Fstatvfs {}, {:X}", id, stat as *mut StatVfs as usize);
+        let files = self.files.lock();
+        if files.contains_key(&id) {
+            let mut fs = self.fs.borrow_mut();
+            let free = fs.free_clusters()? as u64;
+
+            stat.f_bsize = fs.bpb.cluster_size() as u32;
+            stat.f_blocks = fs.bpb.cluster_count() as u64;
+            stat.f_bfree = free;
+            stat.f_bavail = free;
+
+            Ok(0)
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        // println!("Fsync {}", id);
+        let mut files = self.files.lock();
+        let mut fmaps = self.fmaps.lock();
+        if let Some(file) = files.get_mut(&id) {
+            file.sync(&mut fmaps, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
+        // println!("Ftruncate {}, {}", id, len);
+        let mut files = self.files.lock();
+        if let Some(file) = files.get_mut(&id) {
+            file.truncate(len, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn futimens(&self, id: usize, times: &[TimeSpec]) -> Result<usize> {
+        // println!("Futimens {}, {}", id, times.len());
+        let mut files = self.files.lock();
+        if let Some(file) = files.get_mut(&id) {
+            file.utimens(times, &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        // println!("Close {}", id);
+        let mut files = self.files.lock();
+        let mut fmaps = self.fmaps.lock();
+        if let Some(mut file) = files.remove(&id) {
+            let mut fs = self.fs.borrow_mut();
+            let result = file.funmap(&mut fmaps, &mut fs);
+            fs.close_inode(file.block())?;
+            result
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use syscall::error::{EACCES, ENOENT};
+    use syscall::flag::{O_CREAT, O_DIRECTORY, O_RDONLY, O_RDWR, O_WRONLY};
+    use syscall::scheme::Scheme;
+
+    use filesystem::memdisk::{check, names, read_file, volume};
+
+    use super::FileScheme;
+
+    #[test]
+    fn frename_follows_the_handle_not_its_path() {
+        let scheme = FileScheme::new("fat".to_string(), volume(64, ""));
+        let old = scheme.open(b"/a", O_CREAT | O_RDWR, 0, 0).unwrap();
+        scheme.write(old, b"old file").unwrap();
+        scheme.unlink(b"/a", 0, 0).unwrap();
+        let new = scheme.open(b"/a", O_CREAT | O_RDWR, 0, 0).unwrap();
+        scheme.write(new, b"new file").unwrap();
+
+        // The first handle's file is gone, whatever now has its name
+        assert_eq!(scheme.frename(old, b"/b", 0, 0).unwrap_err().errno, ENOENT);
+        assert_eq!(scheme.frename(new, b"/c", 0, 0).unwrap(), 0);
+
+        let mut path = [0; 16];
+        let count = scheme.fpath(new, &mut path).unwrap();
+        assert_eq!(&path[..count], b"fat:/c");
+        scheme.close(old).unwrap();
+        scheme.close(new).unwrap();
+
+        let mut fs = scheme.fs.into_inner();
+        assert_eq!(names(&mut fs, ""), ["c"]);
+        assert_eq!(read_file(&mut fs, "c"), b"new file");
+        check(&mut fs);
+    }
+
+    #[test]
+    fn permission_checks() {
+        let scheme = FileScheme::new("fat".to_string(), volume(64, "uid=1000,gid=100,umask=022"));
+        let file = scheme.open(b"/f", O_CREAT | O_RDWR, 0, 0).unwrap();
+        scheme.close(file).unwrap();
+
+        // Others may read but not write, nor create or remove in the root
+        assert_eq!(scheme.open(b"/g", O_CREAT | O_RDWR, 2000, 1).unwrap_err().errno, EACCES);
+        assert_eq!(scheme.open(b"/f", O_WRONLY, 2000, 1).unwrap_err().errno, EACCES);
+        assert_eq!(scheme.unlink(b"/f", 2000, 1).unwrap_err().errno, EACCES);
+        let file = scheme.open(b"/f", O_RDONLY, 2000, 1).unwrap();
+        assert_eq!(scheme.frename(file, b"/h", 2000, 1).unwrap_err().errno, EACCES);
+        scheme.close(file).unwrap();
+
+        // The owner given at mount time may do all of that
+        let file = scheme.open(b"/g", O_CREAT | O_RDWR, 1000, 100).unwrap();
+        scheme.close(file).unwrap();
+        scheme.unlink(b"/f", 1000, 100).unwrap();
+
+        let mut fs = scheme.fs.into_inner();
+        assert_eq!(names(&mut fs, ""), ["g"]);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn chmod_sets_read_only_on_files_only() {
+        let scheme = FileScheme::new("fat".to_string(), volume(64, "uid=1000,gid=100"));
+        let dir = scheme.open(b"/d", O_CREAT | O_DIRECTORY | O_RDONLY, 1000, 100).unwrap();
+        scheme.close(dir).unwrap();
+        let file = scheme.open(b"/d/f", O_CREAT | O_RDWR, 1000, 100).unwrap();
+        scheme.close(file).unwrap();
+
+        scheme.chmod(b"/d/f", 0o444, 1000, 100).unwrap();
+        scheme.chmod(b"/d", 0o555, 1000, 100).unwrap();
+        assert_eq!(scheme.open(b"/d/f", O_WRONLY, 1000, 100).unwrap_err().errno, EACCES);
+        let file = scheme.open(b"/d/g", O_CREAT | O_RDWR, 1000, 100).unwrap();
+        scheme.close(file).unwrap();
+
+        let mut fs = scheme.fs.into_inner();
+        assert!(fs.find_node("d/f").unwrap().entry.is_read_only());
+        assert!(! fs.find_node("d").unwrap().entry.is_read_only());
+        assert_eq!(names(&mut fs, "d"), ["f", "g"]);
+        check(&mut fs);
+    }
 }
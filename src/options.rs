@@ -0,0 +1,168 @@
+//! Mount options, passed to `redox-fatd` as `-o option[=value],...`
+
+use filesystem::{Codepage, ShortnamePolicy};
+
+/// When reading a file updates its access date
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtimePolicy {
+    /// Never
+    NoAtime,
+    /// When the date differs from the one recorded, so at most once a day
+    RelAtime,
+}
+
+impl Default for AtimePolicy {
+    fn default() -> AtimePolicy {
+        AtimePolicy::RelAtime
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MountOptions {
+    pub shortname: ShortnamePolicy,
+    pub codepage: Codepage,
+    /// Owner reported for every file
+    pub uid: u32,
+    /// Group reported for every file
+    pub gid: u32,
+    /// Permission bits removed from files
+    pub fmask: u16,
+    /// Permission bits removed from directories
+    pub dmask: u16,
+    /// Minutes the local time FAT records is ahead of UTC
+    pub time_offset: i32,
+    pub atime: AtimePolicy,
+}
+
+impl Default for MountOptions {
+    fn default() -> MountOptions {
+        MountOptions {
+            shortname: ShortnamePolicy::default(),
+            codepage: Codepage::default(),
+            uid: 0,
+            gid: 0,
+            fmask: 0o022,
+            dmask: 0o022,
+            time_offset: 0,
+            atime: AtimePolicy::default(),
+        }
+    }
+}
+
+fn parse_id(value: &str) -> Result<u32, String> {
+    value.parse().map_err(|_| format!("invalid id '{}'", value))
+}
+
+fn parse_mask(value: &str) -> Result<u16, String> {
+    match u16::from_str_radix(value, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask),
+        _ => Err(format!("invalid mask '{}'", value))
+    }
+}
+
+fn parse_offset(value: &str) -> Result<i32, String> {
+    match value.parse() {
+        Ok(offset) if offset >= -24 * 60 && offset <= 24 * 60 => Ok(offset),
+        _ => Err(format!("invalid time offset '{}'", value))
+    }
+}
+
+impl MountOptions {
+    pub fn parse(options: &str) -> Result<MountOptions, String> {
+        let mut parsed = MountOptions::default();
+        parsed.apply(options)?;
+        Ok(parsed)
+    }
+
+    /// Set the options listed in `options` on top of those already set, so
+    /// repeated `-o` arguments add up
+    pub fn apply(&mut self, options: &str) -> Result<(), String> {
+        for option in options.split(',').filter(|option| ! option.is_empty()) {
+            let mut parts = option.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            match (key, parts.next()) {
+                ("shortname", Some(value)) => self.shortname = value.parse()?,
+                ("codepage", Some(value)) => self.codepage = value.parse()?,
+                ("uid", Some(value)) => self.uid = parse_id(value)?,
+                ("gid", Some(value)) => self.gid = parse_id(value)?,
+                ("umask", Some(value)) => {
+                    let mask = parse_mask(value)?;
+                    self.fmask = mask;
+                    self.dmask = mask;
+                },
+                ("fmask", Some(value)) => self.fmask = parse_mask(value)?,
+                ("dmask", Some(value)) => self.dmask = parse_mask(value)?,
+                ("time_offset", Some(value)) => self.time_offset = parse_offset(value)?,
+                ("tz", Some("UTC")) => self.time_offset = 0,
+                ("noatime", None) => self.atime = AtimePolicy::NoAtime,
+                ("relatime", None) => self.atime = AtimePolicy::RelAtime,
+                _ => return Err(format!("unknown mount option '{}'", option))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortname() {
+        assert_eq!(MountOptions::parse("").unwrap().shortname, ShortnamePolicy::Mixed);
+        assert_eq!(MountOptions::parse("shortname=winnt").unwrap().shortname, ShortnamePolicy::WinNt);
+        for options in &["bogus", "shortname", "shortname=dos"] {
+            assert!(MountOptions::parse(options).is_err(), "{}", options);
+        }
+    }
+
+    #[test]
+    fn apply_adds_up() {
+        let mut options = MountOptions::parse("uid=1000").unwrap();
+        options.apply("gid=1000").unwrap();
+        options.apply("uid=2000,noatime").unwrap();
+        assert_eq!((options.uid, options.gid), (2000, 1000));
+        assert_eq!(options.atime, AtimePolicy::NoAtime);
+    }
+
+    #[test]
+    fn codepage() {
+        assert_eq!(MountOptions::parse("").unwrap().codepage, Codepage::Cp437);
+        assert_eq!(MountOptions::parse("codepage=850").unwrap().codepage, Codepage::Cp850);
+        assert!(MountOptions::parse("codepage=1252").is_err());
+    }
+
+    #[test]
+    fn ownership_and_masks() {
+        let options = MountOptions::parse("").unwrap();
+        assert_eq!((options.uid, options.gid), (0, 0));
+        assert_eq!((options.fmask, options.dmask), (0o022, 0o022));
+
+        let options = MountOptions::parse("uid=1000,gid=100,umask=077,dmask=002").unwrap();
+        assert_eq!((options.uid, options.gid), (1000, 100));
+        assert_eq!((options.fmask, options.dmask), (0o077, 0o002));
+
+        for options in &["uid", "uid=-1", "gid=x", "umask=0778", "fmask=1000"] {
+            assert!(MountOptions::parse(options).is_err(), "{}", options);
+        }
+    }
+
+    #[test]
+    fn time_offset() {
+        assert_eq!(MountOptions::parse("").unwrap().time_offset, 0);
+        assert_eq!(MountOptions::parse("time_offset=-300").unwrap().time_offset, -300);
+        assert_eq!(MountOptions::parse("time_offset=60,tz=UTC").unwrap().time_offset, 0);
+        for options in &["time_offset=1441", "time_offset=", "tz=Europe/Paris"] {
+            assert!(MountOptions::parse(options).is_err(), "{}", options);
+        }
+    }
+
+    #[test]
+    fn atime() {
+        assert_eq!(MountOptions::parse("").unwrap().atime, AtimePolicy::RelAtime);
+        assert_eq!(MountOptions::parse("noatime").unwrap().atime, AtimePolicy::NoAtime);
+        assert_eq!(MountOptions::parse("noatime,relatime").unwrap().atime, AtimePolicy::RelAtime);
+        assert!(MountOptions::parse("noatime=1").is_err());
+    }
+}
@@ -1,11 +1,11 @@
 // The glue that binds DiskPartition to fatfs
 use std::io::{Read, Write, Seek, SeekFrom};
 use std::cmp::{min, max};
-use std::fs::File;
 use std::path::PathBuf;
 //use syscall::error::{Error, Result, EIO};
 use std::io::{Result, Error, ErrorKind};
 use mbr::partition::{Partition, read_partitions};
+use filesystem::Disk;
 use SECTORSIZE;
 
 macro_rules! try_disk {
@@ -28,13 +28,13 @@ pub fn get_partitions(filepath: PathBuf, typ: u8) -> Result<Vec<Partition>> {
 }
 
 #[derive(Debug)]
-pub struct DiskPartition<D: Read + Write + Seek> {
+pub struct DiskPartition<D: Disk> {
     diskfile: D,
     partition: Partition,
     byte_offset: u64,
 }
 
-impl<D: Read + Write + Seek>  DiskPartition<D> {
+impl<D: Disk>  DiskPartition<D> {
     pub fn new(disk: D, part: Partition) -> Self {
         DiskPartition {
             diskfile: disk,
@@ -42,24 +42,40 @@ impl<D: Read + Write + Seek>  DiskPartition<D> {
             byte_offset: 0
         }
     }
-    fn get_size(&self) -> u32 {
-        self.partition.p_size * SECTORSIZE
+    fn get_size(&self) -> u64 {
+        self.partition.p_size as u64 * SECTORSIZE as u64
+    }
+
+    /// Where `byte_offset` lies on the whole disk
+    fn disk_offset(&self) -> u64 {
+        self.partition.p_lba as u64 * SECTORSIZE as u64 + self.byte_offset
+    }
+
+    /// How much of a `len` byte transfer fits before the end of the partition
+    fn transfer_len(&self, len: usize) -> usize {
+        min(len as u64, self.get_size() - self.byte_offset) as usize
     }
 }
 
-impl<D: Read + Write + Seek>  Read for DiskPartition<D> {
+impl<D: Disk>  Read for DiskPartition<D> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        try_disk!(self.diskfile.seek(SeekFrom::Start((SECTORSIZE * self.partition.p_lba) as u64 + self.byte_offset)));
-        let count = try_disk!(self.diskfile.read(buf));
+        let len = self.transfer_len(buf.len());
+        let offset = self.disk_offset();
+        try_disk!(self.diskfile.seek(SeekFrom::Start(offset)));
+        let count = try_disk!(self.diskfile.read(&mut buf[..len]));
+        self.byte_offset += count as u64;
         Ok(count)
     }
 
 }
 
-impl<D: Read + Write + Seek>  Write for DiskPartition<D> {
+impl<D: Disk>  Write for DiskPartition<D> {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        try_disk!(self.diskfile.seek(SeekFrom::Start((SECTORSIZE * self.partition.p_lba) as u64 + self.byte_offset)));
-        let count = try_disk!(self.diskfile.write(buf));
+        let len = self.transfer_len(buf.len());
+        let offset = self.disk_offset();
+        try_disk!(self.diskfile.seek(SeekFrom::Start(offset)));
+        let count = try_disk!(self.diskfile.write(&buf[..len]));
+        self.byte_offset += count as u64;
         Ok(count)
     }
 
@@ -68,12 +84,12 @@ impl<D: Read + Write + Seek>  Write for DiskPartition<D> {
     }
 }
 
-impl<D: Read + Write + Seek>  Seek for DiskPartition<D> {
+impl<D: Disk>  Seek for DiskPartition<D> {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
         
         self.byte_offset = match pos {
             SeekFrom::Current(off)  => max(0, min(self.get_size() as i64, self.byte_offset as i64 + off)) as u64,
-            SeekFrom::Start(off)    => max(0, min(self.get_size() as u64, off)) as u64,
+            SeekFrom::Start(off)    => min(self.get_size(), off),
             SeekFrom::End(off)      => max(0, min(self.get_size() as i64, self.get_size() as i64 + off)) as u64
         };
 
@@ -81,3 +97,9 @@ impl<D: Read + Write + Seek>  Seek for DiskPartition<D> {
     }
 
 }
+
+impl<D: Disk>  Disk for DiskPartition<D> {
+    fn sync(&mut self) -> Result<()> {
+        self.diskfile.sync()
+    }
+}